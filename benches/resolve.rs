@@ -0,0 +1,64 @@
+use std::{fmt::Write, hint::black_box, sync::Arc};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use m2k::{handler::Event, handler::Handler, mappings::Mappings, repeat::Repeats};
+
+// 96 plain notes, 16 of them overridden on channel 2 for hard hits and 16 more
+// with a window for soft hits, which is about as busy as a full keyboard gets
+fn mappings() -> Mappings {
+    let mut source = String::new();
+    for note in 0..96 {
+        let key = 0x41 + note % 26;
+        writeln!(source, "[[mapping]]\nnote = {note}\nkey = {key}").unwrap();
+    }
+    for note in 60..76 {
+        writeln!(
+            source,
+            "[[mapping]]\nnote = {note}\nkey = \"space\"\nchannel = 2\nvelocity_min = 100"
+        )
+        .unwrap();
+        writeln!(
+            source,
+            "[[mapping]]\nnote = {note}\nkey = \"shift\"\nvelocity_min = 1\nvelocity_max = 63"
+        )
+        .unwrap();
+    }
+    source.parse().unwrap()
+}
+
+fn handler() -> Handler {
+    let repeats = Arc::new(Repeats::default());
+    let macros = Arc::new(Repeats::default());
+    Handler::new(repeats, macros, false, false, None, None, None)
+}
+
+fn resolve(c: &mut Criterion) {
+    let mappings = mappings();
+    let mut group = c.benchmark_group("note on and off");
+
+    // Each case takes a different path through the lookup
+    for (name, channel, note, velocity) in [
+        ("unmapped", 0, 120, 100),
+        ("plain", 0, 40, 100),
+        ("velocity window", 0, 64, 30),
+        ("channel", 1, 64, 110),
+    ] {
+        let mut handler = handler();
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let on = Event::NoteOn {
+                    channel,
+                    note,
+                    velocity,
+                };
+                black_box(handler.resolve_inputs(black_box(on), &mappings));
+                let off = Event::NoteOff { channel, note };
+                black_box(handler.resolve_inputs(black_box(off), &mappings));
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, resolve);
+criterion_main!(benches);
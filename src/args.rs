@@ -0,0 +1,159 @@
+use std::{env, path::PathBuf, time::Duration};
+
+use clap::Parser;
+
+/// Translate MIDI messages to keyboard and mouse inputs
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Args {
+    /// Mappings files, later ones overriding earlier ones note by note, defaults
+    /// to m2k.toml in the current or config directory
+    pub config: Vec<PathBuf>,
+
+    /// Print the name and id of every MIDI device and exit
+    #[arg(long)]
+    pub list_devices: bool,
+
+    /// Check that a mappings file is valid without opening any device and exit,
+    /// resolving characters with a US layout unless --layout is passed
+    #[arg(long, value_name = "PATH")]
+    pub config_check: Option<PathBuf>,
+
+    /// Print every mapped note with what it resolves to and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// MIDI device to listen to by name or id, can be repeated or be "all"
+    #[arg(long = "device", value_name = "NAME OR ID")]
+    pub devices: Vec<String>,
+
+    /// MIDI output device to send every received message on to, by name or id
+    #[arg(long, value_name = "NAME OR ID")]
+    pub forward: Option<String>,
+
+    /// Print received messages without asking
+    #[arg(long, overrides_with = "no_debug")]
+    pub debug: bool,
+
+    /// Don't print received messages without asking
+    #[arg(long)]
+    pub no_debug: bool,
+
+    /// Wait for the device to be connected instead of exiting when it isn't
+    #[arg(long)]
+    pub wait_for_device: bool,
+
+    /// How many more times to try opening a device another program is using
+    #[arg(long, value_name = "COUNT", default_value_t = 3)]
+    pub open_retries: u32,
+
+    /// Ask for the device and debug choice again instead of using the saved ones
+    #[arg(long)]
+    pub reconfigure: bool,
+
+    /// Write the default mappings to m2k.toml and exit
+    #[arg(long)]
+    pub init: bool,
+
+    /// Overwrite an existing m2k.toml with --init
+    #[arg(long, requires = "init")]
+    pub force: bool,
+
+    /// Write every received message to a file
+    #[arg(long, value_name = "PATH")]
+    pub log: Option<PathBuf>,
+
+    /// Keyboard layout to resolve characters in mappings with instead of the current
+    /// one, by its id like 0000040C for French
+    #[arg(long, value_name = "ID", value_parser = layout)]
+    pub layout: Option<String>,
+
+    /// Release keys held for longer than this, in case a note off gets lost
+    #[arg(long, value_name = "SECONDS", value_parser = seconds)]
+    pub release_after: Option<Duration>,
+
+    /// Release everything held whenever another window comes to the foreground
+    #[arg(long)]
+    pub release_on_focus_change: bool,
+
+    /// Exit forcefully if shutting down after Ctrl+C takes longer than this
+    #[arg(long, value_name = "SECONDS", value_parser = seconds, default_value = "5")]
+    pub shutdown_timeout: Duration,
+
+    /// Print a line for every note on nothing is mapped to, while writing a config
+    #[arg(long, conflicts_with_all = ["json", "monitor", "tray", "quiet"])]
+    pub setup: bool,
+
+    /// Ring the terminal bell for unmapped notes as well with --setup
+    #[arg(long, requires = "setup")]
+    pub beep: bool,
+
+    /// Tap a key that does nothing when starting to check that sending input works
+    #[arg(long, conflicts_with = "dry_run")]
+    pub self_test: bool,
+
+    /// Print the inputs each message would send instead of sending them
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Print every message as a line of JSON with what it's mapped to and whether it sent anything
+    #[arg(long, conflicts_with_all = ["dry_run", "debug", "tray"])]
+    pub json: bool,
+
+    /// Show held notes and recent messages in a live view instead of printing them
+    #[arg(long, conflicts_with_all = ["dry_run", "json"])]
+    pub monitor: bool,
+
+    /// Print how long messages took from being received by Windows to their
+    /// inputs being sent when exiting
+    #[arg(long)]
+    pub measure_latency: bool,
+
+    /// Run in the background with an icon in the notification area instead of a console
+    #[arg(long, conflicts_with_all = ["dry_run", "monitor", "debug"])]
+    pub tray: bool,
+
+    /// Print errors and prompts without colors, like when NO_COLOR is set
+    #[arg(long)]
+    pub no_color: bool,
+
+    /// Print the causes and Windows error codes of errors along with them
+    #[arg(long)]
+    pub verbose: bool,
+
+    /// Only print errors and never ask anything, which needs --device with several devices
+    #[arg(long, conflicts_with_all = ["dry_run", "monitor", "debug"])]
+    pub quiet: bool,
+}
+
+impl Args {
+    // None when it should be asked, the last flag wins if both are passed
+    pub fn debug(&self) -> Option<bool> {
+        match (self.debug, self.no_debug) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        }
+    }
+
+    // Any non-empty NO_COLOR counts, https://no-color.org
+    pub fn color(&self) -> bool {
+        let no_color = env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+        !self.no_color && !no_color
+    }
+}
+
+fn layout(value: &str) -> Result<String, String> {
+    if value.len() == 8 && value.chars().all(|c| c.is_ascii_hexdigit()) {
+        Ok(value.to_owned())
+    } else {
+        Err("expected 8 hexadecimal digits".to_owned())
+    }
+}
+
+fn seconds(value: &str) -> Result<Duration, String> {
+    match value.parse().map(Duration::try_from_secs_f64) {
+        Ok(Ok(duration)) if !duration.is_zero() => Ok(duration),
+        _ => Err("expected a positive number of seconds".to_owned()),
+    }
+}
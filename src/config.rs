@@ -0,0 +1,1618 @@
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    fmt,
+    ops::{Range, RangeInclusive},
+    time::Duration,
+};
+
+use miette::LabeledSpan;
+use serde::{
+    de::{self, SeqAccess, Unexpected, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use toml::Spanned;
+use windows::Win32::UI::Input::KeyboardAndMouse::{
+    VIRTUAL_KEY, VK_C, VK_D, VK_E, VK_F, VK_G, VK_SPACE,
+};
+
+use crate::{
+    keys,
+    mappings::{Action, Step},
+    notes,
+};
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileMappings {
+    // Octave of middle C (60) in note names, 4 like Yamaha and 3 like Roland
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub middle_c_octave: Option<i8>,
+    // Note ons repeated this soon after the previous one are ignored
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub debounce_ms: Option<u64>,
+    // Keys are released no sooner than this after going down, for every mapping
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_key_ms: Option<u64>,
+    // Semitones added to incoming notes before looking them up
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transpose: Option<i8>,
+    // Message types dropped before anything else looks at them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ignore: Vec<MessageType>,
+    // Held down from the first note on until exiting, around every other input
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hold_modifier: Option<Keys>,
+    // Pressed by notes no layer maps
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_key: Option<Keys>,
+    // Shuts down like Ctrl+C when held together
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_chord: Option<Located<Vec<Note>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub velocity: Option<FileVelocity>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub channel: Vec<Located<FileChannel>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mapping: Vec<Located<FileMapping>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cc: Vec<Located<FileControlChange>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub relative_cc: Vec<Located<FileRelativeControlChange>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cc14: Vec<Located<FileFineControlChange>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pitchbend: Option<FilePitchBend>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chord: Vec<Located<FileChord>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sustain: Vec<Located<FileSustain>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aftertouch: Vec<Located<FileAftertouch>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sysex: Vec<Located<FileSysEx>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transport: Option<FileTransport>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub layer: Vec<Located<FileLayer>>,
+}
+
+impl FileMappings {
+    pub fn hardcoded() -> Self {
+        let mapping = [
+            // C3 -> space
+            (48, VK_SPACE),
+            // C4 -> C
+            (60, VK_C),
+            // D4 -> D
+            (62, VK_D),
+            // E4 -> E
+            (64, VK_E),
+            // F4 -> F
+            (65, VK_F),
+            // G4 -> G
+            (67, VK_G),
+        ]
+        .into_iter()
+        .map(|(note, key)| FileMapping {
+            note: NoteRange(note..=note),
+            key: Some(FileKey::Key(Keys(vec![key]))),
+            ..Default::default()
+        })
+        .map(Located::from)
+        .collect();
+
+        Self {
+            mapping,
+            ..Default::default()
+        }
+    }
+
+    // Settings the later file sets win and its rows come after the earlier
+    // ones, which is what makes rows for the same notes override them. Layers
+    // with the same name are merged the same way.
+    pub fn merge(&mut self, other: FileMappings) {
+        self.middle_c_octave = other.middle_c_octave.or(self.middle_c_octave);
+        self.debounce_ms = other.debounce_ms.or(self.debounce_ms);
+        self.min_key_ms = other.min_key_ms.or(self.min_key_ms);
+        self.transpose = other.transpose.or(self.transpose);
+        self.hold_modifier = other.hold_modifier.or(self.hold_modifier.take());
+        self.default_key = other.default_key.or(self.default_key.take());
+        self.exit_chord = other.exit_chord.or(self.exit_chord.take());
+        self.velocity = other.velocity.or(self.velocity.take());
+        self.ignore.extend(other.ignore);
+        self.channel.extend(other.channel);
+        self.mapping.extend(other.mapping);
+        self.cc.extend(other.cc);
+        self.relative_cc.extend(other.relative_cc);
+        self.cc14.extend(other.cc14);
+        self.pitchbend = other.pitchbend.or(self.pitchbend.take());
+        self.chord.extend(other.chord);
+        self.sustain.extend(other.sustain);
+        self.aftertouch.extend(other.aftertouch);
+        self.sysex.extend(other.sysex);
+        self.transport = other.transport.or(self.transport.take());
+        for layer in other.layer {
+            let named = self.layer.iter_mut().find(|existing| {
+                existing.value.name.is_some() && existing.value.name == layer.value.name
+            });
+            match named {
+                Some(existing) => existing.value.merge(layer.value),
+                None => self.layer.push(layer),
+            }
+        }
+    }
+}
+
+impl FileLayer {
+    fn merge(&mut self, other: FileLayer) {
+        self.program = other.program.or(self.program);
+        self.mapping.extend(other.mapping);
+        self.cc.extend(other.cc);
+        self.relative_cc.extend(other.relative_cc);
+        self.cc14.extend(other.cc14);
+        self.pitchbend = other.pitchbend.or(self.pitchbend.take());
+        self.chord.extend(other.chord);
+        self.sustain.extend(other.sustain);
+        self.aftertouch.extend(other.aftertouch);
+        self.sysex.extend(other.sysex);
+        self.transport = other.transport.or(self.transport.take());
+    }
+}
+
+// Labels and help for a mappings file that parsed but doesn't make sense
+pub struct Invalid {
+    pub labels: Vec<LabeledSpan>,
+    pub help: &'static str,
+}
+
+impl Invalid {
+    fn new<'a>(
+        labels: impl IntoIterator<Item = (&'a Option<Range<usize>>, &'static str)>,
+        help: &'static str,
+    ) -> Self {
+        let labels = labels
+            .into_iter()
+            .filter_map(|(span, label)| Some(LabeledSpan::at(span.clone()?, label)))
+            .collect();
+        Self { labels, help }
+    }
+}
+
+impl FileMappings {
+    pub fn validate(&self) -> Result<(), Invalid> {
+        check_table(
+            &self.mapping,
+            &self.cc,
+            &self.pitchbend,
+            &self.chord,
+            &self.sustain,
+            &self.aftertouch,
+            &self.sysex,
+        )?;
+        check_relative(&self.relative_cc, &self.cc)?;
+        check_fine(&self.cc14, &self.cc, &self.relative_cc)?;
+        for layer in &self.layer {
+            let layer = &layer.value;
+            check_table(
+                &layer.mapping,
+                &layer.cc,
+                &layer.pitchbend,
+                &layer.chord,
+                &layer.sustain,
+                &layer.aftertouch,
+                &layer.sysex,
+            )?;
+            check_relative(&layer.relative_cc, &layer.cc)?;
+            check_fine(&layer.cc14, &layer.cc, &layer.relative_cc)?;
+        }
+        self.check_channels()?;
+        self.check_programs()?;
+        self.check_exit_chord()?;
+        self.check_modifiers()
+    }
+
+    fn check_channels(&self) -> Result<(), Invalid> {
+        let mut seen = HashMap::new();
+        for channel in &self.channel {
+            if !(1..=16).contains(&channel.value.channel) {
+                return Err(Invalid::new(
+                    [(&channel.span, "channel out of range")],
+                    "channels are numbered from 1 to 16",
+                ));
+            }
+            if let Some(first) = seen.insert(channel.value.channel, channel.span.clone()) {
+                return Err(Invalid::new(
+                    [
+                        (&first, "first configured here"),
+                        (&channel.span, "configured again here"),
+                    ],
+                    "each channel can only have a single `[[channel]]` block",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_programs(&self) -> Result<(), Invalid> {
+        let mut seen = HashMap::new();
+        for layer in &self.layer {
+            let program = match layer.value.program {
+                Some(program) => program,
+                None => continue,
+            };
+            if !(1..=128).contains(&program) {
+                return Err(Invalid::new(
+                    [(&layer.span, "program out of range")],
+                    "programs are numbered from 1 to 128",
+                ));
+            }
+            if let Some(first) = seen.insert(program, layer.span.clone()) {
+                return Err(Invalid::new(
+                    [
+                        (&first, "first switched to here"),
+                        (&layer.span, "switched to again here"),
+                    ],
+                    "each program can only switch to a single layer",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_exit_chord(&self) -> Result<(), Invalid> {
+        match &self.exit_chord {
+            Some(exit_chord) if exit_chord.value.len() < 2 => Err(Invalid::new(
+                [(&exit_chord.span, "not enough notes")],
+                "the exit chord needs at least two notes so it isn't played by accident",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_modifiers(&self) -> Result<(), Invalid> {
+        let names: Vec<&str> = self
+            .layer
+            .iter()
+            .filter_map(|layer| layer.value.name.as_deref())
+            .collect();
+        let mappings = self
+            .mapping
+            .iter()
+            .chain(self.layer.iter().flat_map(|layer| &layer.value.mapping));
+        for mapping in mappings {
+            if let Some(name) = &mapping.value.modifier_layer {
+                if !names.contains(&name.as_str()) {
+                    return Err(Invalid::new(
+                        [(&mapping.span, "no layer with this name")],
+                        "`modifier_layer` must be the name of a `[[layer]]`",
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// The checks that apply to the top level mappings and to each layer alike
+fn check_table(
+    mapping: &[Located<FileMapping>],
+    cc: &[Located<FileControlChange>],
+    pitchbend: &Option<FilePitchBend>,
+    chord: &[Located<FileChord>],
+    sustain: &[Located<FileSustain>],
+    aftertouch: &[Located<FileAftertouch>],
+    sysex: &[Located<FileSysEx>],
+) -> Result<(), Invalid> {
+    check_actions(mapping)?;
+    check_duplicates(mapping)?;
+    check_cc(cc)?;
+    check_pitch_bend(pitchbend)?;
+    check_chords(chord)?;
+    check_sustains(sustain)?;
+    check_aftertouch(aftertouch)?;
+    check_sysex(sysex)
+}
+
+fn check_actions(mapping: &[Located<FileMapping>]) -> Result<(), Invalid> {
+    for mapping in mapping {
+        if mapping
+            .value
+            .channel
+            .is_some_and(|channel| !(1..=16).contains(&channel))
+        {
+            return Err(Invalid::new(
+                [(&mapping.span, "channel out of range")],
+                "channels are numbered from 1 to 16",
+            ));
+        }
+        if let (Some(min), Some(max)) = (mapping.value.velocity_min, mapping.value.velocity_max) {
+            if min > max {
+                return Err(Invalid::new(
+                    [(&mapping.span, "empty velocity window")],
+                    "`velocity_min` can't be higher than `velocity_max` or no velocity would match",
+                ));
+            }
+        }
+        if mapping.value.action().is_none() {
+            return Err(Invalid::new(
+                [(&mapping.span, "no single action for this note")],
+                "each mapping needs exactly one of `key`, `scancode`, `tap`, `mouse`, `text`, `exec`, `macro`, `note_out` or `modifier_layer`",
+            ));
+        }
+        if !mapping.value.positional() {
+            return Err(Invalid::new(
+                [(&mapping.span, "not one key per note")],
+                "ranges take either a single key or a list with one key for each note",
+            ));
+        }
+        if let Some(codes) = &mapping.value.scancode {
+            let valid = |&code: &u16| matches!(code, 0x01..=0xFF | 0xE001..=0xE0FF);
+            if codes.codes().is_empty() || !codes.codes().iter().all(valid) {
+                return Err(Invalid::new(
+                    [(&mapping.span, "invalid scan code")],
+                    "scan codes go from 0x01 to 0xFF, with 0xE0 in front for extended keys like 0xE048",
+                ));
+            }
+        }
+        if mapping.value.invert && !mapping.value.invertible() {
+            return Err(Invalid::new(
+                [(&mapping.span, "can't be inverted")],
+                "`invert` only works with `key`, `scancode` and mouse buttons, without `toggle` or `repeat_ms`",
+            ));
+        }
+        if mapping.value.exec.as_ref().is_some_and(Vec::is_empty) {
+            return Err(Invalid::new(
+                [(&mapping.span, "no program to run")],
+                "`exec` starts with the program followed by its arguments",
+            ));
+        }
+        if mapping.value.sequence.as_ref().is_some_and(Vec::is_empty) {
+            return Err(Invalid::new(
+                [(&mapping.span, "no steps to play")],
+                "`macro` is a list of keys and `{ delay_ms = ... }` pauses",
+            ));
+        }
+        if mapping.value.macro_overlap.is_some() && mapping.value.sequence.is_none() {
+            return Err(Invalid::new(
+                [(&mapping.span, "no macro to overlap")],
+                "`macro_overlap` only applies to `macro` mappings",
+            ));
+        }
+        if let Some(note) = &mapping.value.note_out {
+            if note.0 as usize + mapping.value.note.0.len() > 128 {
+                return Err(Invalid::new(
+                    [(&mapping.span, "sends notes out of range")],
+                    "ranges send the notes counting up from `note_out`, which go up to 127",
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Duplicates are very likely copy-paste mistakes since the last one would win
+fn check_duplicates(mapping: &[Located<FileMapping>]) -> Result<(), Invalid> {
+    let mut seen = HashMap::new();
+    for mapping in mapping {
+        let FileMapping {
+            note,
+            channel,
+            velocity_min,
+            velocity_max,
+            ..
+        } = &mapping.value;
+
+        // Overlapping ranges conflict on every note they share
+        for note in note.0.clone() {
+            let id = (note, *channel, *velocity_min, *velocity_max);
+            if let Some(first) = seen.insert(id, mapping.span.clone()) {
+                return Err(Invalid::new(
+                    [
+                        (&first, "first mapped here"),
+                        (&mapping.span, "mapped again here"),
+                    ],
+                    "notes can only be mapped once per channel and velocity window",
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_cc(cc: &[Located<FileControlChange>]) -> Result<(), Invalid> {
+    let mut seen = HashMap::new();
+    for cc in cc {
+        check_controller(cc.value.controller, &cc.span)?;
+        if cc.value.step.is_some_and(|step| !(1..=127).contains(&step)) {
+            return Err(Invalid::new(
+                [(&cc.span, "step out of range")],
+                "control change values go from 0 to 127, so steps go from 1 to 127",
+            ));
+        }
+        if let Some(first) = seen.insert(cc.value.controller, &cc.span) {
+            return Err(Invalid::new(
+                [
+                    (first, "first mapped here"),
+                    (&cc.span, "mapped again here"),
+                ],
+                "controllers can only be mapped once",
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn check_controller(controller: u8, span: &Option<Range<usize>>) -> Result<(), Invalid> {
+    if controller > 127 {
+        return Err(Invalid::new(
+            [(span, "controller out of range")],
+            "controllers are numbered from 0 to 127",
+        ));
+    }
+    Ok(())
+}
+
+// A controller sends either absolute or relative values, so it can't be both
+fn check_relative(
+    relative: &[Located<FileRelativeControlChange>],
+    cc: &[Located<FileControlChange>],
+) -> Result<(), Invalid> {
+    let mut seen = HashMap::new();
+    for relative in relative {
+        let controller = relative.value.controller;
+        check_controller(controller, &relative.span)?;
+        if let Some(first) = seen.insert(controller, &relative.span) {
+            return Err(Invalid::new(
+                [
+                    (first, "first mapped here"),
+                    (&relative.span, "mapped again here"),
+                ],
+                "controllers can only be mapped once",
+            ));
+        }
+        if let Some(cc) = cc.iter().find(|cc| cc.value.controller == controller) {
+            return Err(Invalid::new(
+                [
+                    (&cc.span, "mapped here"),
+                    (&relative.span, "mapped again as relative here"),
+                ],
+                "controllers can only be mapped by either `cc` or `relative_cc`",
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Both controllers of a pair are taken by it, and the MSB has to be one of the
+// 32 controllers the standard pairs up
+fn check_fine(
+    fine: &[Located<FileFineControlChange>],
+    cc: &[Located<FileControlChange>],
+    relative: &[Located<FileRelativeControlChange>],
+) -> Result<(), Invalid> {
+    let mut seen: HashMap<u8, &Option<Range<usize>>> = HashMap::new();
+    for cc in cc {
+        seen.insert(cc.value.controller, &cc.span);
+    }
+    for relative in relative {
+        seen.insert(relative.value.controller, &relative.span);
+    }
+
+    for pair in fine {
+        if pair.value.msb > 31 {
+            return Err(Invalid::new(
+                [(&pair.span, "MSB out of range")],
+                "14-bit pairs have their MSB on controllers 0 to 31, and their LSB 32 higher by default",
+            ));
+        }
+        let lsb = pair.value.lsb();
+        if lsb > 127 || lsb == pair.value.msb {
+            return Err(Invalid::new(
+                [(&pair.span, "invalid LSB")],
+                "the LSB is another controller from 0 to 127",
+            ));
+        }
+        if pair
+            .value
+            .step
+            .is_some_and(|step| !(1..=16383).contains(&step))
+        {
+            return Err(Invalid::new(
+                [(&pair.span, "step out of range")],
+                "14-bit values go from 0 to 16383, so steps go from 1 to 16383",
+            ));
+        }
+        for controller in [pair.value.msb, lsb] {
+            if let Some(span) = seen.insert(controller, &pair.span) {
+                return Err(Invalid::new(
+                    [(span, "mapped here"), (&pair.span, "mapped again here")],
+                    "controllers can only be mapped once by either `cc`, `relative_cc` or `cc14`",
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_pitch_bend(pitchbend: &Option<FilePitchBend>) -> Result<(), Invalid> {
+    let pitch_bend = match pitchbend {
+        Some(pitch_bend) => pitch_bend,
+        None => return Ok(()),
+    };
+    for bend in pitch_bend.up.iter().chain(&pitch_bend.down) {
+        if bend.value.threshold > PITCH_BEND_MAX {
+            return Err(Invalid::new(
+                [(&bend.span, "threshold out of range")],
+                "pitch bend values go from 0 to 16383 with the center at 8192",
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn check_chords(chord: &[Located<FileChord>]) -> Result<(), Invalid> {
+    for chord in chord {
+        if chord.value.notes.len() < 2 {
+            return Err(Invalid::new(
+                [(&chord.span, "not enough notes")],
+                "chords need at least two notes, use a `[[mapping]]` for single notes",
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn check_sustains(sustain: &[Located<FileSustain>]) -> Result<(), Invalid> {
+    for sustain in sustain {
+        if sustain.value.notes.is_empty() {
+            return Err(Invalid::new(
+                [(&sustain.span, "no notes")],
+                "sustains need at least one note to hold the keys",
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn check_aftertouch(aftertouch: &[Located<FileAftertouch>]) -> Result<(), Invalid> {
+    for aftertouch in aftertouch {
+        if aftertouch.value.threshold > 127 {
+            return Err(Invalid::new(
+                [(&aftertouch.span, "threshold out of range")],
+                "pressure values go from 0 to 127",
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn check_sysex(sysex: &[Located<FileSysEx>]) -> Result<(), Invalid> {
+    for sysex in sysex {
+        let FileSysEx {
+            bytes, prefix_len, ..
+        } = &sysex.value;
+        if bytes.is_empty() {
+            return Err(Invalid::new(
+                [(&sysex.span, "no bytes to match")],
+                "`bytes` needs at least one byte, usually starting with 0xF0",
+            ));
+        }
+        if prefix_len.is_some_and(|len| len == 0 || len > bytes.len()) {
+            return Err(Invalid::new(
+                [(&sysex.span, "prefix length out of range")],
+                "`prefix_len` goes from 1 to the number of `bytes`",
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Settings applying to every message on one channel, whichever layer is active
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileChannel {
+    pub channel: u8,
+    // Used instead of the top level transpose for this channel
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transpose: Option<i8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+}
+
+// A separate set of mappings switched to by a Program Change message or held
+// by a modifier note, the top level mappings being the default layer
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileLayer {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub program: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mapping: Vec<Located<FileMapping>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cc: Vec<Located<FileControlChange>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub relative_cc: Vec<Located<FileRelativeControlChange>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cc14: Vec<Located<FileFineControlChange>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pitchbend: Option<FilePitchBend>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chord: Vec<Located<FileChord>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sustain: Vec<Located<FileSustain>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aftertouch: Vec<Located<FileAftertouch>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sysex: Vec<Located<FileSysEx>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transport: Option<FileTransport>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileMapping {
+    pub note: NoteRange,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<FileKey>,
+    // Hardware scan codes instead of virtual keys, for games reading input
+    // through DirectInput
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scancode: Option<ScanCodes>,
+    // Keys tapped one after the other on note on, unlike `key` holding them together
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tap: Option<Keys>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mouse: Option<Mouse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    // Program and arguments started on note on without waiting for it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exec: Option<Vec<String>>,
+    #[serde(rename = "macro", skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<Vec<FileStep>>,
+    // What playing the note again does while its macro is still playing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub macro_overlap: Option<Overlap>,
+    // Note sent on the --forward output instead of a key, ranges sending the
+    // notes counting up from it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note_out: Option<Note>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub velocity_min: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub velocity_max: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repeat_ms: Option<FileRepeat>,
+    // Name of a layer whose mappings take precedence while the note is held
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modifier_layer: Option<String>,
+    // Whether a note on latches the action until the next note on
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub toggle: bool,
+    // Whether the key goes down on note off and up on note on
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub invert: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_hold_ms: Option<FileHold>,
+    // Note ons within this long of the mapping last firing are ignored
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cooldown_ms: Option<u64>,
+    // Shown next to the action when printing messages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+// Keys tapped one after the other, or a pause between them
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum FileStep {
+    Delay { delay_ms: u64 },
+    Keys(Keys),
+}
+
+impl FileStep {
+    fn step(&self) -> Step {
+        match self {
+            FileStep::Delay { delay_ms } => Step::Delay(Duration::from_millis(*delay_ms)),
+            FileStep::Keys(keys) => Step::Keys(keys.0.clone()),
+        }
+    }
+}
+
+// Milliseconds from the softest to the hardest hit, for the shortest time the
+// action stays pressed or how often it repeats
+#[derive(Clone, Copy, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileHold {
+    pub soft: u64,
+    pub hard: u64,
+}
+
+// Either the same interval for every hit or one depending on how hard it was
+#[derive(Clone, Copy, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum FileRepeat {
+    Fixed(u64),
+    Velocity(FileHold),
+}
+
+impl FileMapping {
+    // None unless exactly one action is specified
+    pub fn action(&self) -> Option<Action> {
+        let mut actions = [
+            self.key.as_ref().map(FileKey::action),
+            self.scancode
+                .as_ref()
+                .map(|codes| Action::ScanCodes(codes.codes().to_vec())),
+            self.tap.as_ref().map(|keys| Action::Tap(keys.0.clone())),
+            self.mouse.map(Action::Mouse),
+            self.text.clone().map(Action::Text),
+            self.exec.clone().map(Action::Exec),
+            self.sequence
+                .as_ref()
+                .map(|steps| Action::Macro(steps.iter().map(FileStep::step).collect())),
+            self.note_out.as_ref().map(|note| Action::NoteOut(note.0)),
+            self.modifier_layer.clone().map(Action::Layer),
+        ]
+        .into_iter()
+        .flatten();
+
+        match (actions.next(), actions.next()) {
+            (Some(action), None) => Some(action),
+            _ => None,
+        }
+    }
+
+    // Only actions with a down and an up can be swapped around
+    fn invertible(&self) -> bool {
+        let holds = matches!(
+            self.action(),
+            Some(Action::Keys(_) | Action::ScanCodes(_) | Action::Mouse(Mouse::Button(_)))
+        );
+        holds && !self.toggle && self.repeat_ms.is_none()
+    }
+
+    // Whether the keys can be matched to the notes of a range, a single key
+    // going to every note however many keys it's typed with
+    fn positional(&self) -> bool {
+        let len = match (&self.key, &self.scancode) {
+            (Some(FileKey::List(strokes)), _) => strokes.len(),
+            (_, Some(codes)) => codes.codes().len(),
+            _ => return true,
+        };
+        self.note.0.len() == 1 || len == 1 || len == self.note.0.len()
+    }
+
+    // The action of the nth note of the range, a list of keys over a range
+    // giving each note its own key
+    pub fn action_at(&self, index: usize) -> Option<Action> {
+        match (self.action()?, &self.key) {
+            (Action::Keys(_), Some(FileKey::List(strokes)))
+                if self.note.0.len() > 1 && strokes.len() > 1 =>
+            {
+                Some(Action::Keys(strokes.get(index)?.0.clone()))
+            }
+            (Action::ScanCodes(codes), _) if self.note.0.len() > 1 && codes.len() > 1 => {
+                Some(Action::ScanCodes(vec![*codes.get(index)?]))
+            }
+            (Action::NoteOut(note), _) => {
+                let note = note as usize + index;
+                Some(Action::NoteOut(
+                    u8::try_from(note).ok().filter(|&note| note < 128)?,
+                ))
+            }
+            (action, _) => Some(action),
+        }
+    }
+}
+
+// Either a single scan code or an array of them, extended keys like the arrows
+// having 0xE0 in front like 0xE048
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ScanCodes {
+    One(u16),
+    Many(Vec<u16>),
+}
+
+impl ScanCodes {
+    pub fn codes(&self) -> &[u16] {
+        match self {
+            ScanCodes::One(code) => std::slice::from_ref(code),
+            ScanCodes::Many(codes) => codes,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileVelocity {
+    #[serde(default)]
+    pub curve: Curve,
+}
+
+// How a macro still playing when its note is played again is handled
+#[derive(Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Overlap {
+    // The new note on is dropped
+    Ignore,
+    // The macro stops where it is and starts over
+    #[default]
+    Restart,
+    // The macro plays again once it's done, as many times as the note was played
+    Queue,
+}
+
+// Applied to velocities before they're compared against velocity windows
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Curve {
+    #[default]
+    Linear,
+    // Soft hits come out softer, for controllers that make it too easy to hit hard
+    #[serde(alias = "exponential")]
+    Exp,
+    // Soft hits come out harder, for controllers that make it too hard to hit hard
+    #[serde(alias = "logarithmic")]
+    Log,
+}
+
+impl Curve {
+    // Both ends stay where they are, only the values in between move
+    pub fn apply(self, velocity: u8) -> u8 {
+        let velocity = velocity.min(127) as f32 / 127.0;
+        let curved = match self {
+            Curve::Linear => velocity,
+            Curve::Exp => velocity * velocity,
+            Curve::Log => velocity.sqrt(),
+        };
+        (curved * 127.0).round() as u8
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageType {
+    NoteOn,
+    NoteOff,
+    Cc,
+    PitchBend,
+    ProgramChange,
+    // Both channel and polyphonic pressure
+    Aftertouch,
+    SysEx,
+    // Start, continue and stop
+    Transport,
+}
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Mouse {
+    Button(MouseButton),
+    Move {
+        #[serde(rename = "move")]
+        by: (i32, i32),
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileControlChange {
+    pub controller: u8,
+    pub key: Keys,
+    // Taps the keys whenever the value moves into another step instead of
+    // holding them from 64 and up
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub step: Option<u8>,
+}
+
+// Keys tapped once for each step a controller sending increments like a jog
+// wheel moves by, one key for each direction
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileRelativeControlChange {
+    pub controller: u8,
+    pub up: Keys,
+    pub down: Keys,
+    #[serde(default)]
+    pub encoding: Encoding,
+}
+
+// Keys for a pair of controllers sending the high and low 7 bits of a 14-bit
+// value, behaving like `cc` with 16384 values instead of 128
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileFineControlChange {
+    pub msb: u8,
+    // 32 above the MSB like the standard says when left out
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lsb: Option<u8>,
+    pub key: Keys,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub step: Option<u16>,
+}
+
+impl FileFineControlChange {
+    pub fn lsb(&self) -> u8 {
+        self.lsb.unwrap_or(self.msb.saturating_add(32))
+    }
+}
+
+// How an increment is written in the 7 bits of a control change value
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    // 1 is +1 and 127 is -1
+    #[default]
+    TwosComplement,
+    // The highest bit is the sign, 1 is +1 and 65 is -1
+    SignedBit,
+    // 64 is no change, 65 is +1 and 63 is -1
+    BinaryOffset,
+}
+
+impl Encoding {
+    pub fn delta(self, value: u8) -> i8 {
+        let value = value & 0x7F;
+        match self {
+            Encoding::TwosComplement if value >= 64 => value as i8 - 64 - 64,
+            Encoding::TwosComplement => value as i8,
+            Encoding::SignedBit if value >= 64 => -((value - 64) as i8),
+            Encoding::SignedBit => value as i8,
+            Encoding::BinaryOffset => value as i8 - 64,
+        }
+    }
+}
+
+// Keys held while all of the notes are held together
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileChord {
+    pub notes: Vec<Note>,
+    pub key: Keys,
+}
+
+// Keys tapped when a System Exclusive message starts with the first `prefix_len`
+// bytes, all of them by default
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileSysEx {
+    pub bytes: Vec<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefix_len: Option<usize>,
+    pub key: Keys,
+}
+
+// Keys tapped for the realtime messages a sequencer sends when it starts
+// playing, continues after a pause and stops
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileTransport {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start: Option<Keys>,
+    #[serde(rename = "continue", skip_serializing_if = "Option::is_none")]
+    pub resume: Option<Keys>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Keys>,
+}
+
+// Keys held for as long as any of the notes is held, on top of what each note
+// is mapped to
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileSustain {
+    pub notes: Vec<Note>,
+    pub key: Keys,
+}
+
+// Keys held while the pressure is past the threshold, for a single note with
+// polyphonic aftertouch or for the whole channel without a note
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileAftertouch {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<Note>,
+    pub threshold: u8,
+    pub key: Keys,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hysteresis: Option<u8>,
+}
+
+pub const PITCH_BEND_MAX: u16 = 0x3FFF;
+
+// Keys held while the wheel is bent past a threshold, released once it comes
+// back past the threshold by more than the hysteresis
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FilePitchBend {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub up: Option<Located<FileBend>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub down: Option<Located<FileBend>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hysteresis: Option<u16>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileBend {
+    pub threshold: u16,
+    pub key: Keys,
+}
+
+// Settings which change how the rest of the file is parsed, read on their own first
+#[derive(Deserialize)]
+pub struct Prelude {
+    pub middle_c_octave: Option<i8>,
+}
+
+#[derive(Clone, Copy)]
+pub struct Context {
+    // Only the TOML deserializer knows where values are
+    pub spans: bool,
+    pub middle_c_octave: i8,
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self {
+            spans: false,
+            middle_c_octave: notes::MIDDLE_C_OCTAVE,
+        }
+    }
+}
+
+thread_local! {
+    static CONTEXT: Cell<Context> = Cell::new(Context::default());
+}
+
+pub fn with_context<R>(context: Context, f: impl FnOnce() -> R) -> R {
+    CONTEXT.with(|current| current.set(context));
+    let result = f();
+    CONTEXT.with(|current| current.set(Context::default()));
+    result
+}
+
+// Remembers where a value was in the file for error reporting
+pub struct Located<T> {
+    pub span: Option<Range<usize>>,
+    pub value: T,
+}
+
+impl<T> From<T> for Located<T> {
+    fn from(value: T) -> Self {
+        Self { span: None, value }
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Located<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if !CONTEXT.with(Cell::get).spans {
+            return T::deserialize(deserializer).map(Self::from);
+        }
+
+        let spanned = Spanned::<T>::deserialize(deserializer)?;
+        Ok(Self {
+            span: Some(spanned.span()),
+            value: spanned.into_inner(),
+        })
+    }
+}
+
+impl<T: Serialize> Serialize for Located<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.value.serialize(serializer)
+    }
+}
+
+// Either a MIDI note number or a note name like "C4", "F#3" or "Bb5"
+pub struct Note(pub u8);
+
+struct NoteVisitor;
+
+impl<'de> Visitor<'de> for NoteVisitor {
+    type Value = Note;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a MIDI note number from 0 to 127 or a note name")
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+        match u8::try_from(v) {
+            Ok(note @ 0..=127) => Ok(Note(note)),
+            _ => Err(E::invalid_value(Unexpected::Signed(v), &self)),
+        }
+    }
+
+    // Unsigned integers come from JSON and YAML
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+        match u8::try_from(v) {
+            Ok(note @ 0..=127) => Ok(Note(note)),
+            _ => Err(E::invalid_value(Unexpected::Unsigned(v), &self)),
+        }
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        let middle_c_octave = CONTEXT.with(Cell::get).middle_c_octave;
+        match notes::parse_note_name(v, middle_c_octave) {
+            Some(note) => Ok(Note(note)),
+            None => Err(E::invalid_value(Unexpected::Str(v), &self)),
+        }
+    }
+}
+
+impl Note {
+    // Either end of a range, as a number or a name
+    fn parse(v: &str) -> Option<u8> {
+        match v.parse::<u8>() {
+            Ok(note) => Some(note).filter(|&note| note < 128),
+            Err(_) => NoteVisitor
+                .visit_str::<de::value::Error>(v)
+                .ok()
+                .map(|Note(note)| note),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Note {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(NoteVisitor)
+    }
+}
+
+// A single note like `Note` or an inclusive range of them like "36-48" or "C2-C3"
+pub struct NoteRange(pub RangeInclusive<u8>);
+
+// Only there to fill in the rest of a mapping built with its note
+impl Default for NoteRange {
+    fn default() -> Self {
+        Self(0..=0)
+    }
+}
+
+impl<'de> Deserialize<'de> for NoteRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct NoteRangeVisitor;
+
+        impl<'de> Visitor<'de> for NoteRangeVisitor {
+            type Value = NoteRange;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a MIDI note number, a note name or a range of them like \"36-48\"")
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                NoteVisitor.visit_i64(v).map(NoteRange::from)
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                NoteVisitor.visit_u64(v).map(NoteRange::from)
+            }
+
+            // Names can contain dashes themselves so every split is tried
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                if let Ok(note) = NoteVisitor.visit_str::<E>(v) {
+                    return Ok(note.into());
+                }
+                let range = v
+                    .match_indices('-')
+                    .filter_map(|(index, _)| {
+                        let start = Note::parse(&v[..index])?;
+                        let end = Note::parse(&v[index + 1..])?;
+                        Some(start..=end)
+                    })
+                    .find(|range| !range.is_empty());
+                match range {
+                    Some(range) => Ok(NoteRange(range)),
+                    None => Err(E::invalid_value(Unexpected::Str(v), &self)),
+                }
+            }
+        }
+
+        deserializer.deserialize_any(NoteRangeVisitor)
+    }
+}
+
+impl From<Note> for NoteRange {
+    fn from(Note(note): Note) -> Self {
+        Self(note..=note)
+    }
+}
+
+impl Serialize for NoteRange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (start, end) = (*self.0.start(), *self.0.end());
+        if start == end {
+            Note(start).serialize(serializer)
+        } else {
+            serializer.serialize_str(&format!("{start}-{end}"))
+        }
+    }
+}
+
+// Written back as a number since names depend on the octave convention
+impl Serialize for Note {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(self.0)
+    }
+}
+
+// Either a single key or an array of keys pressed in order and released in reverse
+pub struct Keys(pub Vec<VIRTUAL_KEY>);
+
+struct Key(VIRTUAL_KEY);
+
+// A key as written in the file, with the modifiers characters are typed with in
+// front of them
+struct Stroke(Vec<VIRTUAL_KEY>);
+
+// A single key, a list of them, or a single character no key of the layout
+// types which is typed as text instead. Lists keep each of their keys apart
+// since a range gives every note its own, even for characters typed with
+// modifiers.
+pub enum FileKey {
+    Key(Keys),
+    List(Vec<Keys>),
+    Text(String),
+}
+
+impl FileKey {
+    fn action(&self) -> Action {
+        match self {
+            FileKey::Key(keys) => Action::Keys(keys.0.clone()),
+            FileKey::List(strokes) => {
+                Action::Keys(strokes.iter().flat_map(|keys| keys.0.clone()).collect())
+            }
+            FileKey::Text(text) => Action::Text(text.clone()),
+        }
+    }
+}
+
+impl Serialize for Keys {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match &self.0[..] {
+            [key] => Key(*key).serialize(serializer),
+            keys => serializer.collect_seq(keys.iter().map(|&key| Key(key))),
+        }
+    }
+}
+
+impl Serialize for Key {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match keys::key_name(self.0) {
+            Some(name) => serializer.serialize_str(&name),
+            None => serializer.serialize_u16(self.0 .0),
+        }
+    }
+}
+
+impl Serialize for FileKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            FileKey::Key(keys) => keys.serialize(serializer),
+            // Characters typed with modifiers come back as the keys they're typed with
+            FileKey::List(strokes) => serializer.collect_seq(
+                strokes
+                    .iter()
+                    .flat_map(|keys| keys.0.iter().map(|&key| Key(key))),
+            ),
+            FileKey::Text(text) => serializer.serialize_str(text),
+        }
+    }
+}
+
+struct KeysVisitor;
+
+impl<'de> Visitor<'de> for KeysVisitor {
+    type Value = Keys;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a key or an array of keys")
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+        KeyVisitor.visit_i64(v).map(|Stroke(keys)| Keys(keys))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+        KeyVisitor.visit_u64(v).map(|Stroke(keys)| Keys(keys))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        KeyVisitor.visit_str(v).map(|Stroke(keys)| Keys(keys))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut keys = vec![];
+        while let Some(Stroke(stroke)) = seq.next_element()? {
+            keys.extend(stroke);
+        }
+
+        if keys.is_empty() {
+            Err(de::Error::invalid_length(0, &self))
+        } else {
+            Ok(Keys(keys))
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Keys {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(KeysVisitor)
+    }
+}
+
+impl<'de> Deserialize<'de> for FileKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FileKeyVisitor;
+
+        impl<'de> Visitor<'de> for FileKeyVisitor {
+            type Value = FileKey;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                KeysVisitor.expecting(f)
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                KeysVisitor.visit_i64(v).map(FileKey::Key)
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                KeysVisitor.visit_u64(v).map(FileKey::Key)
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                let mut chars = v.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) if keys::parse_char(c).is_none() => {
+                        Ok(FileKey::Text(v.to_owned()))
+                    }
+                    _ => KeysVisitor.visit_str(v).map(FileKey::Key),
+                }
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut strokes = vec![];
+                while let Some(Stroke(stroke)) = seq.next_element()? {
+                    strokes.push(Keys(stroke));
+                }
+
+                if strokes.is_empty() {
+                    Err(de::Error::invalid_length(0, &self))
+                } else {
+                    Ok(FileKey::List(strokes))
+                }
+            }
+        }
+
+        deserializer.deserialize_any(FileKeyVisitor)
+    }
+}
+
+struct KeyVisitor;
+
+impl<'de> Visitor<'de> for KeyVisitor {
+    type Value = Stroke;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a virtual key code, a key name or a character")
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+        match u8::try_from(v) {
+            Ok(key) if keys::is_virtual_key(key) => Ok(Stroke(vec![VIRTUAL_KEY(key as u16)])),
+            _ => Err(E::invalid_value(Unexpected::Signed(v), &self)),
+        }
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+        match u8::try_from(v) {
+            Ok(key) if keys::is_virtual_key(key) => Ok(Stroke(vec![VIRTUAL_KEY(key as u16)])),
+            _ => Err(E::invalid_value(Unexpected::Unsigned(v), &self)),
+        }
+    }
+
+    // Single characters are looked up in the layout before names so "A" is shift+a
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        let mut chars = v.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            if let Some(keys) = keys::parse_char(c) {
+                return Ok(Stroke(keys));
+            }
+        }
+        match keys::parse_key_name(v) {
+            Some(key) => Ok(Stroke(vec![key])),
+            None => Err(E::invalid_value(Unexpected::Str(v), &self)),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Stroke {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(KeyVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use miette::Diagnostic;
+
+    use super::*;
+    use crate::{mappings::Mappings, Error};
+
+    // Each label with the part of the source it points at, along with the help
+    fn invalid(source: &str) -> (Vec<(String, String)>, Option<String>) {
+        let error = match source.parse::<Mappings>() {
+            Err(Error::Config(error)) => error,
+            Err(error) => panic!("unexpected error {error}"),
+            Ok(_) => panic!("accepted {source}"),
+        };
+        let labels = error
+            .labels()
+            .into_iter()
+            .flatten()
+            .map(|label| {
+                let span = &source[label.offset()..label.offset() + label.len()];
+                (
+                    label.label().unwrap_or_default().to_owned(),
+                    span.to_owned(),
+                )
+            })
+            .collect();
+        (labels, error.help().map(|help| help.to_string()))
+    }
+
+    fn labels(source: &str) -> Vec<String> {
+        invalid(source)
+            .0
+            .into_iter()
+            .map(|(label, _)| label)
+            .collect()
+    }
+
+    #[test]
+    fn malformed_toml() {
+        let (labels, _) = invalid("[[mapping]\nnote = 60\n");
+        assert_eq!(labels.len(), 1);
+    }
+
+    #[test]
+    fn unknown_fields() {
+        let (labels, _) = invalid("[[mapping]]\nnote = 60\nkee = \"f1\"\n");
+        assert!(labels[0].0.contains("unknown field `kee`"), "{labels:?}");
+    }
+
+    #[test]
+    fn duplicate_notes() {
+        let source = r#"
+            [[mapping]]
+            note = 60
+            key = "f1"
+
+            [[mapping]]
+            note = 60
+            key = "f2"
+        "#;
+        let (labels, help) = invalid(source);
+        assert_eq!(labels[0].0, "first mapped here");
+        assert!(labels[0].1.contains("\"f1\""), "{labels:?}");
+        assert_eq!(labels[1].0, "mapped again here");
+        assert!(labels[1].1.contains("\"f2\""), "{labels:?}");
+        assert_eq!(
+            help.as_deref(),
+            Some("notes can only be mapped once per channel and velocity window"),
+        );
+    }
+
+    #[test]
+    fn overlapping_ranges() {
+        let source = r#"
+            [[mapping]]
+            note = "C4-E4"
+            key = "f1"
+
+            [[mapping]]
+            note = "D4"
+            key = "f2"
+        "#;
+        assert_eq!(labels(source), ["first mapped here", "mapped again here"]);
+    }
+
+    #[test]
+    fn separate_velocity_windows() {
+        let source = r#"
+            [[mapping]]
+            note = 60
+            key = "f1"
+            velocity_max = 63
+
+            [[mapping]]
+            note = 60
+            key = "f2"
+            velocity_min = 64
+        "#;
+        assert!(source.parse::<Mappings>().is_ok());
+    }
+
+    #[test]
+    fn out_of_range_values() {
+        let mapping = |field: &str| format!("[[mapping]]\nnote = 60\nkey = \"f1\"\n{field}\n");
+        assert_eq!(labels(&mapping("channel = 17")), ["channel out of range"]);
+        assert_eq!(
+            labels(&mapping("velocity_min = 100\nvelocity_max = 20")),
+            ["empty velocity window"],
+        );
+        assert_eq!(
+            labels("[[cc]]\ncontroller = 128\nkey = \"f1\"\n"),
+            ["controller out of range"],
+        );
+        assert_eq!(
+            labels("[[cc]]\ncontroller = 11\nkey = \"f1\"\nstep = 0\n"),
+            ["step out of range"],
+        );
+        assert_eq!(
+            labels("[[cc14]]\nmsb = 32\nkey = \"f1\"\n"),
+            ["MSB out of range"],
+        );
+        assert_eq!(
+            labels("[pitchbend]\nup = { threshold = 20000, key = \"f1\" }\n"),
+            ["threshold out of range"],
+        );
+    }
+
+    #[test]
+    fn controllers_mapped_twice() {
+        let cc = "[[cc]]\ncontroller = 1\nkey = \"f1\"\n";
+        assert_eq!(
+            labels(&format!("{cc}\n{cc}")),
+            ["first mapped here", "mapped again here"],
+        );
+        assert_eq!(
+            labels(&format!(
+                "{cc}\n[[relative_cc]]\ncontroller = 1\nup = \"f2\"\ndown = \"f3\"\n"
+            )),
+            ["mapped here", "mapped again as relative here"],
+        );
+        assert_eq!(
+            labels(&format!("{cc}\n[[cc14]]\nmsb = 1\nkey = \"f2\"\n")),
+            ["mapped here", "mapped again here"],
+        );
+    }
+
+    #[test]
+    fn curves_keep_both_ends() {
+        for curve in [Curve::Linear, Curve::Exp, Curve::Log] {
+            assert_eq!(curve.apply(0), 0);
+            assert_eq!(curve.apply(127), 127);
+        }
+        assert!(Curve::Exp.apply(64) < 64);
+        assert!(Curve::Log.apply(64) > 64);
+    }
+
+    #[test]
+    fn encodings() {
+        for (encoding, up, down) in [
+            (Encoding::TwosComplement, 1, 127),
+            (Encoding::SignedBit, 1, 65),
+            (Encoding::BinaryOffset, 65, 63),
+        ] {
+            assert_eq!(encoding.delta(up), 1);
+            assert_eq!(encoding.delta(down), -1);
+        }
+        assert_eq!(Encoding::BinaryOffset.delta(64), 0);
+    }
+}
@@ -0,0 +1,257 @@
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use windows::{
+    core::{ComInterface, HSTRING},
+    Devices::{
+        Enumeration::{DeviceInformation, DeviceInformationUpdate, DeviceWatcher},
+        Midi::{IMidiMessage, MidiInPort, MidiMessageReceivedEventArgs, MidiOutPort},
+    },
+    Foundation::{EventRegistrationToken, TypedEventHandler},
+};
+
+use crate::{is_verbose, report_error, Error};
+
+const OPEN_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+pub fn enumerate() -> Result<(Vec<HSTRING>, Vec<HSTRING>), Error> {
+    enumerate_selector(&MidiInPort::GetDeviceSelector()?)
+}
+
+pub fn enumerate_outputs() -> Result<(Vec<HSTRING>, Vec<HSTRING>), Error> {
+    enumerate_selector(&MidiOutPort::GetDeviceSelector()?)
+}
+
+fn enumerate_selector(
+    midi_device_selector: &HSTRING,
+) -> Result<(Vec<HSTRING>, Vec<HSTRING>), Error> {
+    let midi_devices = DeviceInformation::FindAllAsyncAqsFilter(midi_device_selector)?.get()?;
+
+    let devices = midi_devices
+        .into_iter()
+        .filter_map(|device| {
+            match device.Name().and_then(|name| Ok((name, device.Id()?))) {
+                Ok(device) => Some(device),
+                // Otherwise there's no telling why a device isn't listed
+                Err(error) => {
+                    if is_verbose() {
+                        let which = device.Id().or_else(|_| device.Name());
+                        let which =
+                            which.map_or_else(|_| "a device".to_owned(), |which| which.to_string());
+                        eprintln!("Skipped {which} without a name or id: {error}");
+                    }
+                    None
+                }
+            }
+        })
+        .fold((vec![], vec![]), |(mut names, mut ids), (name, id)| {
+            names.push(name);
+            ids.push(id);
+            (names, ids)
+        });
+    Ok(devices)
+}
+
+// Exact id matches win over case-insensitive name matches
+pub fn find(query: &str, names: &[HSTRING], ids: &[HSTRING]) -> Result<usize, Error> {
+    if let Some(index) = ids.iter().position(|id| *id == query) {
+        return Ok(index);
+    }
+
+    let query_lowercase = query.to_lowercase();
+    let matches: Vec<usize> = names
+        .iter()
+        .enumerate()
+        .filter(|(_, name)| name.to_string().to_lowercase().contains(&query_lowercase))
+        .map(|(index, _)| index)
+        .collect();
+
+    match matches[..] {
+        [] => Err(Error::DeviceNotFound(query.to_owned())),
+        [index] => Ok(index),
+        _ => Err(Error::AmbiguousDevice {
+            query: query.to_owned(),
+            candidates: matches
+                .iter()
+                .map(|&index| format!("{} ({})", names[index], ids[index]))
+                .collect::<Vec<_>>()
+                .join(", "),
+        }),
+    }
+}
+
+// A device along with when it was opened, which the timestamps of its messages
+// count from
+pub type Opened = (MidiInPort, Instant);
+
+// Another program having the device open makes opening it fail, which is
+// retried in case that program is about to let go of it
+pub fn open(name: &HSTRING, id: &HSTRING, retries: u32) -> Result<Opened, Error> {
+    let mut attempt = 0;
+    loop {
+        match MidiInPort::FromIdAsync(id).and_then(|operation| operation.get()) {
+            Ok(device) => return Ok((device, Instant::now())),
+            Err(error) if attempt == retries => {
+                return Err(Error::DeviceBusy {
+                    name: name.to_string(),
+                    source: error,
+                })
+            }
+            Err(_) => {
+                attempt += 1;
+                status!("Couldn't open {name}, retrying ({attempt} of {retries})");
+                thread::sleep(OPEN_RETRY_DELAY);
+            }
+        }
+    }
+}
+
+// Output devices are matched like input devices
+pub fn open_output(query: &str) -> Result<MidiOutPort, Error> {
+    let (names, ids) = enumerate_outputs()?;
+    let index = find(query, &names, &ids)?;
+    // The interface isn't Send but the class behind it is
+    let port = MidiOutPort::FromIdAsync(&ids[index])?.get()?.cast()?;
+    Ok(port)
+}
+
+// Called with each message and when it was received
+pub type MessageHandler = Arc<dyn Fn(IMidiMessage, Instant) + Send + Sync>;
+
+// Registers the handler on the device and registers it again on a fresh port
+// every time the same device is plugged back in, until closed
+pub struct Connection {
+    watcher: DeviceWatcher,
+    port: Arc<Mutex<Port>>,
+}
+
+// The open port along with its handler's registration, None while unplugged.
+// Closing keeps a reconnection already under way from opening it again.
+#[derive(Default)]
+struct Port {
+    open: Option<(MidiInPort, EventRegistrationToken)>,
+    closed: bool,
+}
+
+pub fn connect(
+    device: MidiInPort,
+    opened: Instant,
+    handler: MessageHandler,
+) -> Result<Connection, Error> {
+    let id = device.DeviceId()?;
+    let token = register(&device, opened, handler.clone())?;
+    let port = Arc::new(Mutex::new(Port {
+        open: Some((device, token)),
+        closed: false,
+    }));
+
+    let watcher = DeviceInformation::CreateWatcherAqsFilter(&MidiInPort::GetDeviceSelector()?)?;
+    watcher.Removed(
+        &TypedEventHandler::<DeviceWatcher, DeviceInformationUpdate>::new({
+            let id = id.clone();
+            let port = port.clone();
+            move |_, update| {
+                if let Some(update) = update.as_ref() {
+                    if let Err(error) = disconnected(update, &id, &port) {
+                        report_error(error);
+                    }
+                }
+                Ok(())
+            }
+        }),
+    )?;
+    watcher.Added(&TypedEventHandler::<DeviceWatcher, DeviceInformation>::new(
+        {
+            let port = port.clone();
+            move |_, information| {
+                if let Some(information) = information.as_ref() {
+                    if let Err(error) = reconnected(information, &id, &port, &handler) {
+                        report_error(error);
+                    }
+                }
+                Ok(())
+            }
+        },
+    ))?;
+    watcher.Start()?;
+
+    Ok(Connection { watcher, port })
+}
+
+impl Connection {
+    // Once this returns no more messages reach the handler, so nothing can
+    // press keys again after they're released
+    pub fn close(&self) -> Result<(), windows::core::Error> {
+        self.watcher.Stop()?;
+        let mut port = self.port.lock().unwrap();
+        port.closed = true;
+        match port.open.take() {
+            Some((device, token)) => close(&device, token),
+            None => Ok(()),
+        }
+    }
+}
+
+fn register(
+    device: &MidiInPort,
+    opened: Instant,
+    handler: MessageHandler,
+) -> Result<EventRegistrationToken, windows::core::Error> {
+    device.MessageReceived(
+        &TypedEventHandler::<MidiInPort, MidiMessageReceivedEventArgs>::new(move |_, event| {
+            if let Some(event) = event.as_ref() {
+                let message = event.Message()?;
+                let received = opened + Duration::from(message.Timestamp()?);
+                handler(message, received);
+            }
+            Ok(())
+        }),
+    )
+}
+
+fn close(device: &MidiInPort, token: EventRegistrationToken) -> Result<(), windows::core::Error> {
+    device.RemoveMessageReceived(token)?;
+    device.Close()
+}
+
+fn disconnected(
+    update: &DeviceInformationUpdate,
+    id: &HSTRING,
+    port: &Mutex<Port>,
+) -> Result<(), windows::core::Error> {
+    if update.Id()? != *id {
+        return Ok(());
+    }
+
+    if let Some((device, token)) = port.lock().unwrap().open.take() {
+        close(&device, token)?;
+        status!("Device disconnected, reconnecting…");
+    }
+    Ok(())
+}
+
+fn reconnected(
+    information: &DeviceInformation,
+    id: &HSTRING,
+    port: &Mutex<Port>,
+    handler: &MessageHandler,
+) -> Result<(), windows::core::Error> {
+    if information.Id()? != *id {
+        return Ok(());
+    }
+
+    // The watcher also reports devices that are already present when it starts
+    let mut port = port.lock().unwrap();
+    if port.open.is_some() || port.closed {
+        return Ok(());
+    }
+
+    let device = MidiInPort::FromIdAsync(id)?.get()?;
+    let token = register(&device, Instant::now(), handler.clone())?;
+    port.open = Some((device, token));
+    status!("Device reconnected");
+    Ok(())
+}
@@ -0,0 +1,80 @@
+use std::{cell::RefCell, sync::mpsc, thread};
+
+use windows::Win32::{
+    Foundation::{HWND, LPARAM, WPARAM},
+    System::Threading::GetCurrentThreadId,
+    UI::{
+        Accessibility::{SetWinEventHook, UnhookWinEvent, HWINEVENTHOOK},
+        WindowsAndMessaging::{
+            DispatchMessageW, GetMessageW, PostThreadMessageW, TranslateMessage,
+            EVENT_SYSTEM_FOREGROUND, MSG, WINEVENT_OUTOFCONTEXT, WM_QUIT,
+        },
+    },
+};
+
+use crate::Error;
+
+// Calls back whenever another window comes to the foreground, so keys held while
+// switching windows don't stay down in the previous one. The hook only runs while
+// its thread pumps messages.
+pub struct Focus(u32);
+
+thread_local! {
+    // The hook is called on the thread that set it
+    static CHANGED: RefCell<Option<Box<dyn Fn()>>> = const { RefCell::new(None) };
+}
+
+impl Focus {
+    pub fn spawn(changed: impl Fn() + Send + 'static) -> Result<Self, Error> {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || unsafe {
+            CHANGED.with(|callback| *callback.borrow_mut() = Some(Box::new(changed)));
+            let hook = SetWinEventHook(
+                EVENT_SYSTEM_FOREGROUND,
+                EVENT_SYSTEM_FOREGROUND,
+                None,
+                Some(foreground),
+                0,
+                0,
+                WINEVENT_OUTOFCONTEXT,
+            );
+            if hook.is_invalid() {
+                let _ = sender.send(Err(windows::core::Error::from_win32()));
+                return;
+            }
+            let _ = sender.send(Ok(GetCurrentThreadId()));
+
+            let mut message = MSG::default();
+            while GetMessageW(&mut message, None, 0, 0).as_bool() {
+                TranslateMessage(&message);
+                DispatchMessageW(&message);
+            }
+            UnhookWinEvent(hook);
+        });
+
+        let thread = receiver.recv().unwrap()?;
+        Ok(Self(thread))
+    }
+}
+
+impl Drop for Focus {
+    fn drop(&mut self) {
+        let _ = unsafe { PostThreadMessageW(self.0, WM_QUIT, WPARAM(0), LPARAM(0)) };
+    }
+}
+
+unsafe extern "system" fn foreground(
+    _hook: HWINEVENTHOOK,
+    _event: u32,
+    _window: HWND,
+    _object: i32,
+    _child: i32,
+    _thread: u32,
+    _time: u32,
+) {
+    CHANGED.with(|changed| {
+        if let Some(changed) = &*changed.borrow() {
+            changed();
+        }
+    });
+}
@@ -0,0 +1,1668 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use windows::{
+    core::ComInterface,
+    Devices::Midi::{
+        IMidiMessage, MidiChannelPressureMessage, MidiControlChangeMessage, MidiMessageType,
+        MidiNoteOffMessage, MidiNoteOnMessage, MidiOutPort, MidiPitchBendChangeMessage,
+        MidiPolyphonicKeyPressureMessage, MidiProgramChangeMessage, MidiSystemExclusiveMessage,
+    },
+    Storage::Streams::DataReader,
+    Win32::UI::Input::KeyboardAndMouse::{INPUT, KEYEVENTF_KEYUP, VIRTUAL_KEY},
+};
+
+use crate::{
+    config::MessageType,
+    input::{self, KEYDOWN},
+    latency::Latency,
+    log::{self, Log},
+    mappings::{Action, Chord, Fine, Mappings, Sustain, Transport},
+    monitor::Monitor,
+    notes,
+    repeat::Repeats,
+    report_error,
+    schedule::{self, Job, Tag},
+};
+
+// Channel mode messages sent by panic buttons, which release everything no
+// matter what the mappings say
+const ALL_SOUND_OFF: u8 = 120;
+const ALL_NOTES_OFF: u8 = 123;
+
+// MIDI messages the handler cares about, decoupled from the Windows types
+#[derive(Clone, Debug)]
+pub enum Event {
+    NoteOn {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    NoteOff {
+        channel: u8,
+        note: u8,
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    PitchBend {
+        channel: u8,
+        value: u16,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    ChannelPressure {
+        channel: u8,
+        pressure: u8,
+    },
+    PolyPressure {
+        channel: u8,
+        note: u8,
+        pressure: u8,
+    },
+    // The whole message, from 0xF0 to 0xF7
+    SysEx {
+        data: Vec<u8>,
+    },
+    Transport(Transport),
+}
+
+impl Event {
+    pub fn ty(&self) -> MessageType {
+        match self {
+            Event::NoteOn { .. } => MessageType::NoteOn,
+            Event::NoteOff { .. } => MessageType::NoteOff,
+            Event::ControlChange { .. } => MessageType::Cc,
+            Event::PitchBend { .. } => MessageType::PitchBend,
+            Event::ProgramChange { .. } => MessageType::ProgramChange,
+            Event::ChannelPressure { .. } | Event::PolyPressure { .. } => MessageType::Aftertouch,
+            Event::SysEx { .. } => MessageType::SysEx,
+            Event::Transport(_) => MessageType::Transport,
+        }
+    }
+
+    // System messages aren't sent on any channel
+    pub fn channel(&self) -> Option<u8> {
+        match *self {
+            Event::NoteOn { channel, .. }
+            | Event::NoteOff { channel, .. }
+            | Event::ControlChange { channel, .. }
+            | Event::PitchBend { channel, .. }
+            | Event::ProgramChange { channel, .. }
+            | Event::ChannelPressure { channel, .. }
+            | Event::PolyPressure { channel, .. } => Some(channel),
+            Event::SysEx { .. } | Event::Transport(_) => None,
+        }
+    }
+
+    pub fn decode(message: &IMidiMessage) -> Result<Option<Self>, windows::core::Error> {
+        let event = match message.Type()? {
+            MidiMessageType::NoteOn => {
+                let message: MidiNoteOnMessage = message.cast()?;
+                let (channel, note) = (message.Channel()?, message.Note()?);
+                // Plenty of devices send note offs as note ons without velocity
+                match message.Velocity()? {
+                    0 => Event::NoteOff { channel, note },
+                    velocity => Event::NoteOn {
+                        channel,
+                        note,
+                        velocity,
+                    },
+                }
+            }
+            MidiMessageType::NoteOff => {
+                let message: MidiNoteOffMessage = message.cast()?;
+                Event::NoteOff {
+                    channel: message.Channel()?,
+                    note: message.Note()?,
+                }
+            }
+            MidiMessageType::ControlChange => {
+                let message: MidiControlChangeMessage = message.cast()?;
+                Event::ControlChange {
+                    channel: message.Channel()?,
+                    controller: message.Controller()?,
+                    value: message.ControlValue()?,
+                }
+            }
+            MidiMessageType::PitchBendChange => {
+                let message: MidiPitchBendChangeMessage = message.cast()?;
+                Event::PitchBend {
+                    channel: message.Channel()?,
+                    value: message.Bend()?,
+                }
+            }
+            MidiMessageType::ProgramChange => {
+                let message: MidiProgramChangeMessage = message.cast()?;
+                Event::ProgramChange {
+                    channel: message.Channel()?,
+                    program: message.Program()?,
+                }
+            }
+            MidiMessageType::ChannelPressure => {
+                let message: MidiChannelPressureMessage = message.cast()?;
+                Event::ChannelPressure {
+                    channel: message.Channel()?,
+                    pressure: message.Pressure()?,
+                }
+            }
+            MidiMessageType::PolyphonicKeyPressure => {
+                let message: MidiPolyphonicKeyPressureMessage = message.cast()?;
+                Event::PolyPressure {
+                    channel: message.Channel()?,
+                    note: message.Note()?,
+                    pressure: message.Pressure()?,
+                }
+            }
+            MidiMessageType::SystemExclusive => {
+                let message: MidiSystemExclusiveMessage = message.cast()?;
+                let buffer = message.RawData()?;
+                let mut data = vec![0; buffer.Length()? as usize];
+                DataReader::FromBuffer(&buffer)?.ReadBytes(&mut data)?;
+                Event::SysEx { data }
+            }
+            MidiMessageType::Start => Event::Transport(Transport::Start),
+            MidiMessageType::Continue => Event::Transport(Transport::Continue),
+            MidiMessageType::Stop => Event::Transport(Transport::Stop),
+            _ => return Ok(None),
+        };
+        Ok(Some(event))
+    }
+}
+
+impl Event {
+    // One line with everything about the message, with note names in the
+    // octave convention of the mappings
+    pub fn display(&self, middle_c_octave: i8) -> Line<'_> {
+        Line {
+            event: self,
+            middle_c_octave,
+        }
+    }
+}
+
+pub struct Line<'a> {
+    event: &'a Event,
+    middle_c_octave: i8,
+}
+
+impl fmt::Display for Line<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let note_name = |note| notes::note_name(note, self.middle_c_octave);
+        match *self.event {
+            Event::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => write!(
+                f,
+                "channel {:<2}  note on          {} ({note}) velocity {velocity}",
+                channel + 1,
+                note_name(note),
+            ),
+            Event::NoteOff { channel, note } => write!(
+                f,
+                "channel {:<2}  note off         {} ({note})",
+                channel + 1,
+                note_name(note),
+            ),
+            Event::ControlChange {
+                channel,
+                controller,
+                value,
+            } => write!(
+                f,
+                "channel {:<2}  control change   {controller} value {value}",
+                channel + 1,
+            ),
+            Event::PitchBend { channel, value } => {
+                write!(f, "channel {:<2}  pitch bend       {value}", channel + 1)
+            }
+            Event::ProgramChange { channel, program } => write!(
+                f,
+                "channel {:<2}  program change   {}",
+                channel + 1,
+                program as u16 + 1,
+            ),
+            Event::ChannelPressure { channel, pressure } => {
+                write!(f, "channel {:<2}  channel pressure {pressure}", channel + 1)
+            }
+            Event::PolyPressure {
+                channel,
+                note,
+                pressure,
+            } => write!(
+                f,
+                "channel {:<2}  poly pressure    {} ({note}) pressure {pressure}",
+                channel + 1,
+                note_name(note),
+            ),
+            Event::SysEx { ref data } => write!(f, "sysex {} bytes", data.len()),
+            Event::Transport(transport) => write!(f, "{}", transport.name()),
+        }
+    }
+}
+
+// What a hook registered through the library wants done with an event after
+// seeing it
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Decision {
+    Proceed,
+    // Nothing is sent for the event, it's still printed, logged and forwarded
+    Suppress,
+}
+
+pub type Hook = Arc<dyn Fn(&Event) -> Decision + Send + Sync>;
+
+#[derive(Default)]
+struct Pair {
+    msb: Option<u8>,
+    lsb: Option<u8>,
+    bucket: Option<u16>,
+}
+
+// Actions pressed by each channel and note, so that note off releases the same
+// action note on picked even if the velocity window would resolve differently
+struct Pressed(Vec<Option<Action>>);
+
+impl Pressed {
+    fn new() -> Self {
+        Self(vec![None; 16 * 128])
+    }
+
+    fn index(channel: u8, note: u8) -> usize {
+        (channel as usize % 16) * 128 + (note as usize % 128)
+    }
+
+    fn get(&self, channel: u8, note: u8) -> Option<&Action> {
+        self.0[Self::index(channel, note)].as_ref()
+    }
+
+    fn slot(&mut self, channel: u8, note: u8) -> &mut Option<Action> {
+        &mut self.0[Self::index(channel, note)]
+    }
+}
+
+pub struct Handler {
+    pressed: Pressed,
+    repeats: Arc<Repeats>,
+    macros: Arc<Repeats>,
+    // How many note ons each note got without a note off, so controllers
+    // sending note on again while a note is held don't press it twice
+    down: HashMap<(u8, u8), usize>,
+    // When each note was last accepted, for debouncing, and the notes held whose
+    // note on was debounced
+    triggered: HashMap<(u8, u8), Instant>,
+    debounced: HashSet<(u8, u8)>,
+    // Until when notes with a minimum hold time have to stay pressed
+    holds: HashMap<(u8, u8), Instant>,
+    // Releases waiting for the minimum hold time, cancelled by setting the flag
+    delayed: HashMap<(u8, u8), (Arc<AtomicBool>, Action)>,
+    // Notes whose toggled action is currently latched
+    latched: HashSet<(u8, u8)>,
+    // Notes with an inverted action, pressing it on note off, and the actions
+    // pressed that way until the next note on
+    inverted: HashSet<(u8, u8)>,
+    raised: HashMap<(u8, u8), Action>,
+    // Transposed notes currently held on any channel, and the chords they form
+    held: BTreeSet<u8>,
+    chords: Vec<Chord>,
+    // Sustains currently holding their keys and how many of their notes are held
+    sustains: Vec<(Sustain, usize)>,
+    // Layers held by modifier notes along with the note holding them
+    modifiers: Vec<(u8, u8, String)>,
+    // Last bucket each controller was in, per channel
+    buckets: HashMap<(u8, u8), u8>,
+    // Halves of each 14-bit pair received so far and the bucket they were in,
+    // by channel and MSB controller
+    pairs: HashMap<(u8, u8), Pair>,
+    // Keys held by the up and down pitch bends, kept even if the mappings change
+    bent: [Option<Vec<VIRTUAL_KEY>>; 2],
+    // Keys held by aftertouch by channel and note for polyphonic pressure
+    pressing: Vec<(u8, Option<u8>, Vec<VIRTUAL_KEY>)>,
+    // The hold_modifier keys currently held down
+    holding: Vec<VIRTUAL_KEY>,
+    debug: bool,
+    json: bool,
+    log: Option<Log>,
+    monitor: Option<Monitor>,
+    latency: Option<Latency>,
+    // Where received messages are sent on to and note_out sends its notes,
+    // only the first failure being reported
+    forward: Option<MidiOutPort>,
+    forward_failed: bool,
+    // Set when the message was sent on as another note, and when it started
+    // something sending inputs on its own like a repeat, a macro or a command
+    remapped: bool,
+    started: bool,
+    // Whether to point out note ons nothing is mapped to, and to ring the
+    // terminal bell for them
+    setup: bool,
+    beep: bool,
+    // Called when the exit chord is played, which does nothing without it
+    exit: Option<Arc<dyn Fn() + Send + Sync>>,
+    hook: Option<Hook>,
+}
+
+impl Handler {
+    pub fn new(
+        repeats: Arc<Repeats>,
+        macros: Arc<Repeats>,
+        debug: bool,
+        json: bool,
+        log: Option<Log>,
+        monitor: Option<Monitor>,
+        latency: Option<Latency>,
+    ) -> Self {
+        Self {
+            pressed: Pressed::new(),
+            repeats,
+            macros,
+            down: HashMap::new(),
+            triggered: HashMap::new(),
+            debounced: HashSet::new(),
+            holds: HashMap::new(),
+            delayed: HashMap::new(),
+            latched: HashSet::new(),
+            inverted: HashSet::new(),
+            raised: HashMap::new(),
+            held: BTreeSet::new(),
+            chords: vec![],
+            sustains: vec![],
+            modifiers: vec![],
+            buckets: HashMap::new(),
+            pairs: HashMap::new(),
+            bent: [None, None],
+            pressing: vec![],
+            holding: vec![],
+            debug,
+            json,
+            log,
+            monitor,
+            latency,
+            forward: None,
+            forward_failed: false,
+            remapped: false,
+            started: false,
+            setup: false,
+            beep: false,
+            exit: None,
+            hook: None,
+        }
+    }
+
+    pub fn forward(mut self, output: Option<MidiOutPort>) -> Self {
+        self.forward = output;
+        self
+    }
+
+    pub fn setup(mut self, setup: bool, beep: bool) -> Self {
+        self.setup = setup;
+        self.beep = beep;
+        self
+    }
+
+    pub fn exit(mut self, exit: Option<Arc<dyn Fn() + Send + Sync>>) -> Self {
+        self.exit = exit;
+        self
+    }
+
+    // Called with every event that isn't ignored before resolving it
+    pub fn hook(mut self, hook: Option<Hook>) -> Self {
+        self.hook = hook;
+        self
+    }
+
+    // Messages are sent on to the output after handling them, except for notes
+    // already sent on as other notes. Latency counts from when it was received.
+    pub fn handle(
+        &mut self,
+        message: &IMidiMessage,
+        received: Instant,
+        mappings: &Mappings,
+    ) -> Result<(), windows::core::Error> {
+        self.remapped = false;
+        self.started = false;
+        let result = self.map(message, received, mappings);
+        if !self.remapped {
+            self.send_out(message);
+        }
+        result
+    }
+
+    fn map(
+        &mut self,
+        message: &IMidiMessage,
+        received: Instant,
+        mappings: &Mappings,
+    ) -> Result<(), windows::core::Error> {
+        let event = match Event::decode(message)? {
+            Some(event) => event,
+            None => return Ok(()),
+        };
+        if !accepts(&event, mappings) {
+            return Ok(());
+        }
+        let proceed = self.proceeds(&event);
+
+        let mut mapped = None;
+        if self.debug || self.json || self.log.is_some() || self.monitor.is_some() {
+            mapped = match self.retriggers(&event) {
+                true => Some("ignored, already held".to_owned()),
+                false => self.mapped(&event, mappings),
+            };
+            // The monitor and JSON take over the output so nothing else is printed
+            if self.debug && !self.json && self.monitor.is_none() {
+                println!(
+                    "{} -> {}",
+                    event.display(mappings.middle_c_octave()),
+                    mapped.as_deref().unwrap_or("unmapped"),
+                );
+            }
+            if let Some(log) = &self.log {
+                log.event(&event, mapped.clone());
+            }
+            if let Some(monitor) = &self.monitor {
+                if let Err(error) =
+                    monitor.event(&event, mapped.as_deref(), mappings.middle_c_octave())
+                {
+                    report_error(error);
+                }
+            }
+        }
+
+        if let (
+            true,
+            Event::NoteOn {
+                channel,
+                note,
+                velocity,
+            },
+        ) = (self.setup && proceed, &event)
+        {
+            if mappings
+                .get(*channel, *note, *velocity, self.held_layers())
+                .is_none()
+            {
+                let bell = if self.beep { "\x07" } else { "" };
+                println!(
+                    "NOT MAPPED {}{bell}",
+                    event.display(mappings.middle_c_octave())
+                );
+            }
+        }
+
+        // Printed once it's known whether anything was sent
+        let json = self.json.then(|| event.clone());
+        let inputs = if proceed {
+            self.resolve_inputs(event, mappings)
+        } else {
+            vec![]
+        };
+        // Only inputs sent right away count towards the latency
+        let sent = input::send(inputs) > 0;
+        if let Some(event) = json {
+            println!(
+                "{}",
+                log::json(&event, mapped.as_deref(), sent || self.started)
+            );
+        }
+        if let (true, Some(latency)) = (sent, &self.latency) {
+            latency.record(received.elapsed());
+        }
+        Ok(())
+    }
+
+    // What the event resolves to, or what it releases for a note off
+    fn mapped(&self, event: &Event, mappings: &Mappings) -> Option<String> {
+        let action = match *event {
+            Event::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => {
+                let binding = mappings.get(channel, note, velocity, self.held_layers())?;
+                match &binding.label {
+                    Some(label) => format!("{} ({label})", binding.action),
+                    None => binding.action.to_string(),
+                }
+            }
+            Event::NoteOff { channel, note } => self.pressed.get(channel, note)?.to_string(),
+            Event::ControlChange {
+                controller: ALL_SOUND_OFF | ALL_NOTES_OFF,
+                ..
+            } => "release everything".to_owned(),
+            Event::ControlChange {
+                controller, value, ..
+            } => match (
+                mappings.get_cc14(controller),
+                mappings.get_relative_cc(controller),
+            ) {
+                (Some(fine), _) => Action::Keys(fine.keys.clone()).to_string(),
+                (None, Some(relative)) => {
+                    let (keys, taps) = relative.taps(value)?;
+                    format!("{} x{taps}", Action::Keys(keys.to_vec()))
+                }
+                (None, None) => Action::Keys(mappings.get_cc(controller)?.keys.clone()).to_string(),
+            },
+            Event::PitchBend { value, .. } => {
+                let bend = mappings
+                    .bends()
+                    .iter()
+                    .flatten()
+                    .find(|bend| bend.beyond(value));
+                Action::Keys(bend?.keys.clone()).to_string()
+            }
+            Event::ProgramChange { program, .. } => {
+                format!("layer {}", mappings.program_layer(program)?)
+            }
+            Event::ChannelPressure { pressure, .. } => {
+                self.mapped_pressure(None, pressure, mappings)?
+            }
+            Event::PolyPressure {
+                channel,
+                note,
+                pressure,
+            } => {
+                let note = mappings.transposed(channel, note)?;
+                self.mapped_pressure(Some(note), pressure, mappings)?
+            }
+            Event::SysEx { ref data } => {
+                Action::Keys(mappings.get_sysex(data)?.to_vec()).to_string()
+            }
+            Event::Transport(transport) => {
+                Action::Keys(mappings.get_transport(transport)?.to_vec()).to_string()
+            }
+        };
+        Some(action)
+    }
+
+    fn mapped_pressure(
+        &self,
+        note: Option<u8>,
+        pressure: u8,
+        mappings: &Mappings,
+    ) -> Option<String> {
+        let aftertouch = mappings
+            .aftertouch()
+            .iter()
+            .filter(|aftertouch| aftertouch.note == note)
+            .find(|aftertouch| aftertouch.hold.beyond(pressure.into()))?;
+        Some(Action::Keys(aftertouch.hold.keys.clone()).to_string())
+    }
+
+    // Note ons for notes already held, which don't press anything
+    fn retriggers(&self, event: &Event) -> bool {
+        match *event {
+            Event::NoteOn { channel, note, .. } => self.down.contains_key(&(channel, note)),
+            _ => false,
+        }
+    }
+
+    // Most recently held first
+    fn held_layers(&self) -> impl Iterator<Item = &str> {
+        self.modifiers
+            .iter()
+            .rev()
+            .map(|(_, _, name)| name.as_str())
+    }
+
+    // Runs scripted events through the same filtering and resolution as received
+    // messages and returns every input they'd send, for checking what mappings
+    // do without a device. Repeats, macros and exec still run on their own since
+    // they don't go through the inputs.
+    pub fn replay(
+        &mut self,
+        events: impl IntoIterator<Item = Event>,
+        mappings: &Mappings,
+    ) -> Vec<INPUT> {
+        let mut inputs = vec![];
+        for event in events {
+            if accepts(&event, mappings) && self.proceeds(&event) {
+                inputs.extend(self.resolve_inputs(event, mappings));
+            }
+        }
+        inputs
+    }
+
+    fn proceeds(&self, event: &Event) -> bool {
+        match &self.hook {
+            Some(hook) => hook(event) == Decision::Proceed,
+            None => true,
+        }
+    }
+
+    // Everything short of actually sending the inputs, so it can run without a device
+    pub fn resolve_inputs(&mut self, event: Event, mappings: &Mappings) -> Vec<INPUT> {
+        match event {
+            Event::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => {
+                // Nothing would bring the count back down with note offs ignored
+                if !mappings.ignores(MessageType::NoteOff) {
+                    let down = self.down.entry((channel, note)).or_default();
+                    *down += 1;
+                    if *down > 1 {
+                        // Notes sent on as another note aren't sent on raw either
+                        if let Some(Action::NoteOut(_)) = self.pressed.get(channel, note) {
+                            self.remapped = true;
+                        }
+                        return vec![];
+                    }
+                }
+
+                let now = Instant::now();
+                if let Some(&last) = self.triggered.get(&(channel, note)) {
+                    if now.duration_since(last) < mappings.debounce() {
+                        self.debounced.insert((channel, note));
+                        return vec![];
+                    }
+                }
+                self.triggered.insert((channel, note), now);
+
+                // The note completing the exit chord doesn't press anything, the
+                // others are released while shutting down
+                if self.completes_exit_chord(channel, note, mappings) {
+                    if let Some(exit) = &self.exit {
+                        status!("Exiting after the exit chord");
+                        exit();
+                        return vec![];
+                    }
+                }
+
+                let mut inputs = self.hold_modifier(mappings);
+                inputs.extend(self.release_delayed(channel, note));
+                inputs.extend(self.release_inverted(channel, note));
+                inputs.extend(self.press_note(channel, note, velocity, mappings));
+                inputs.extend(self.press_chords(channel, note, mappings));
+                inputs.extend(self.press_sustains(channel, note, mappings));
+                inputs
+            }
+            Event::NoteOff { channel, note } => {
+                // Note offs without a counted note on still go through, like
+                // for notes whose note on was dropped by the ignore list
+                if let Some(down) = self.down.get_mut(&(channel, note)) {
+                    *down -= 1;
+                    if *down > 0 {
+                        return vec![];
+                    }
+                    self.down.remove(&(channel, note));
+                }
+                // Its note on didn't press anything, chords and sustains included
+                if self.debounced.remove(&(channel, note)) {
+                    return vec![];
+                }
+
+                let mut inputs = self.release_chords(channel, note, mappings);
+                inputs.extend(self.release_sustains(channel, note, mappings));
+                inputs.extend(self.release_pressure(channel, note, mappings));
+                if self.inverted.remove(&(channel, note)) {
+                    inputs.extend(self.press_inverted(channel, note));
+                } else if !self.latched.contains(&(channel, note)) {
+                    inputs.extend(self.release_note(channel, note));
+                }
+                inputs
+            }
+            Event::ControlChange {
+                controller: ALL_SOUND_OFF | ALL_NOTES_OFF,
+                ..
+            } => {
+                status!("Releasing everything after an all notes off message");
+                self.reset()
+            }
+            Event::ControlChange {
+                channel,
+                controller,
+                value,
+            } => {
+                if let Some(fine) = mappings.get_cc14(controller) {
+                    return self.press_fine(channel, controller, value, fine);
+                }
+                // Increments are tapped as they come in since there's nothing to hold
+                if let Some(relative) = mappings.get_relative_cc(controller) {
+                    return match relative.taps(value) {
+                        Some((keys, taps)) => (0..taps).flat_map(|_| input::tap(keys)).collect(),
+                        None => vec![],
+                    };
+                }
+                let mapped = match mappings.get_cc(controller) {
+                    Some(mapped) => mapped,
+                    None => return vec![],
+                };
+                // Nothing is sent until the value moves into another bucket so
+                // a continuous controller doesn't flood the inputs
+                let bucket = mapped.bucket(value);
+                let previous = self.buckets.insert((channel, controller), bucket);
+                if previous.unwrap_or(0) == bucket {
+                    return vec![];
+                }
+                match mapped.step {
+                    Some(_) => input::tap(&mapped.keys),
+                    None if bucket > 0 => input::keys(&mapped.keys, KEYDOWN),
+                    None => input::keys(&mapped.keys, KEYEVENTF_KEYUP),
+                }
+            }
+            Event::PitchBend { value, .. } => {
+                let mut inputs = vec![];
+                for (held, bend) in self.bent.iter_mut().zip(mappings.bends()) {
+                    // Keys the new mappings don't hold anymore are released first
+                    let stale =
+                        held.take_if(|keys| !bend.as_ref().is_some_and(|bend| bend.keys == *keys));
+                    if let Some(keys) = stale {
+                        inputs.extend(input::keys(&keys, KEYEVENTF_KEYUP));
+                    }
+                    let bend = match bend {
+                        Some(bend) => bend,
+                        None => continue,
+                    };
+                    match bend.transition(held.is_some(), value) {
+                        Some(true) => {
+                            inputs.extend(input::keys(&bend.keys, KEYDOWN));
+                            *held = Some(bend.keys.clone());
+                        }
+                        Some(false) => {
+                            if let Some(keys) = held.take() {
+                                inputs.extend(input::keys(&keys, KEYEVENTF_KEYUP));
+                            }
+                        }
+                        None => {}
+                    }
+                }
+                inputs
+            }
+            Event::ChannelPressure { channel, pressure } => {
+                self.press_pressure(channel, None, pressure, mappings)
+            }
+            Event::PolyPressure {
+                channel,
+                note,
+                pressure,
+            } => match mappings.transposed(channel, note) {
+                Some(note) => self.press_pressure(channel, Some(note), pressure, mappings),
+                None => vec![],
+            },
+            Event::ProgramChange { program, .. } => {
+                if let Some(name) = mappings.switch_layer(program) {
+                    status!("Switched to layer {name}");
+                }
+                vec![]
+            }
+            // There's no message for letting go, so the keys are tapped
+            Event::SysEx { data } => match mappings.get_sysex(&data) {
+                Some(keys) => input::tap(keys),
+                None => vec![],
+            },
+            Event::Transport(transport) => match mappings.get_transport(transport) {
+                Some(keys) => input::tap(keys),
+                None => vec![],
+            },
+        }
+    }
+
+    fn press_note(
+        &mut self,
+        channel: u8,
+        note: u8,
+        velocity: u8,
+        mappings: &Mappings,
+    ) -> Vec<INPUT> {
+        // A latched note is released by the next note on, whatever it maps to now
+        if self.latched.remove(&(channel, note)) {
+            return self.release_note(channel, note);
+        }
+        let binding = match mappings.get(channel, note, velocity, self.held_layers()) {
+            Some(binding) => binding,
+            None => return vec![],
+        };
+        if let Some(cooldown) = &binding.cooldown {
+            if !cooldown.ready() {
+                if self.debug && self.monitor.is_none() {
+                    println!("Suppressed {} by its cooldown", binding.action);
+                }
+                return vec![];
+            }
+        }
+        *self.pressed.slot(channel, note) = Some(binding.action.clone());
+        if binding.invert {
+            self.inverted.insert((channel, note));
+            return vec![];
+        }
+        // Whichever of the mapping's and the global minimum is longer
+        match binding.min_hold(velocity).max(mappings.min_key()) {
+            Some(hold) => self.holds.insert((channel, note), Instant::now() + hold),
+            None => self.holds.remove(&(channel, note)),
+        };
+        if binding.toggle {
+            self.latched.insert((channel, note));
+            input::latch(&binding.action);
+        }
+        if let Action::Layer(name) = &binding.action {
+            self.modifiers.push((channel, note, name.clone()));
+            return vec![];
+        }
+        if let Action::NoteOut(out) = binding.action {
+            self.send_note(channel, out, Some(velocity));
+            return vec![];
+        }
+        if let Action::Exec(command) = &binding.action {
+            input::exec(command);
+            self.started = true;
+            return vec![];
+        }
+        if let Action::Macro(steps) = &binding.action {
+            self.macros
+                .play(channel, note, steps.clone(), binding.overlap);
+            self.started = true;
+            return vec![];
+        }
+        if let Some(interval) = binding.repeat(velocity) {
+            self.repeats
+                .start(channel, note, binding.action.clone(), interval);
+            self.started = true;
+            return vec![];
+        }
+
+        input::press(&binding.action)
+    }
+
+    // The value only counts the LSB once it came after the MSB, which resets it
+    // like the standard says, so a device only sending the MSB is still
+    // handled in 7-bit steps
+    fn press_fine(&mut self, channel: u8, controller: u8, value: u8, fine: &Fine) -> Vec<INPUT> {
+        let pair = self.pairs.entry((channel, fine.msb)).or_default();
+        if controller == fine.msb {
+            pair.msb = Some(value);
+            pair.lsb = None;
+        } else {
+            pair.lsb = Some(value);
+        }
+        // Only an LSB is treated as a 7-bit value too
+        let value = match (pair.msb, pair.lsb) {
+            (Some(msb), lsb) => (msb as u16) << 7 | lsb.unwrap_or(0) as u16,
+            (None, lsb) => (lsb.unwrap_or(0) as u16) << 7,
+        };
+
+        let bucket = fine.bucket(value);
+        if pair.bucket.replace(bucket).unwrap_or(0) == bucket {
+            return vec![];
+        }
+        match fine.step {
+            Some(_) => input::tap(&fine.keys),
+            None if bucket > 0 => input::keys(&fine.keys, KEYDOWN),
+            None => input::keys(&fine.keys, KEYEVENTF_KEYUP),
+        }
+    }
+
+    // The modifier goes down before the first note on and is latched so only
+    // exiting, a panic button or focus changing releases it, after which the
+    // next note on presses it again. Reloading the mappings swaps it for the
+    // new one.
+    fn hold_modifier(&mut self, mappings: &Mappings) -> Vec<INPUT> {
+        let keys = mappings.hold_modifier();
+        if keys.is_empty() && self.holding.is_empty() {
+            return vec![];
+        }
+        let released: Vec<VIRTUAL_KEY> = self
+            .holding
+            .iter()
+            .filter(|key| !keys.contains(key))
+            .copied()
+            .collect();
+        let pressed: Vec<VIRTUAL_KEY> = keys
+            .iter()
+            .filter(|&&key| !input::held(key))
+            .copied()
+            .collect();
+        self.holding = keys.to_vec();
+
+        let mut inputs = input::keys(&released, KEYEVENTF_KEYUP);
+        input::latch(&Action::Keys(pressed.clone()));
+        inputs.extend(input::keys(&pressed, KEYDOWN));
+        inputs
+    }
+
+    // The action stays down until the next note on, so it's latched to keep it
+    // from being released for being held too long
+    fn press_inverted(&mut self, channel: u8, note: u8) -> Vec<INPUT> {
+        match self.pressed.slot(channel, note).take() {
+            Some(action) => {
+                input::latch(&action);
+                let inputs = input::press(&action);
+                self.raised.insert((channel, note), action);
+                inputs
+            }
+            None => vec![],
+        }
+    }
+
+    // Only what the note off actually pressed goes up, whatever the note maps
+    // to now, so the first note on doesn't release a key that was never down
+    fn release_inverted(&mut self, channel: u8, note: u8) -> Vec<INPUT> {
+        match self.raised.remove(&(channel, note)) {
+            Some(action) => input::release(&action),
+            None => vec![],
+        }
+    }
+
+    fn completes_exit_chord(&self, channel: u8, note: u8, mappings: &Mappings) -> bool {
+        let exit_chord = mappings.exit_chord();
+        match mappings.transposed(channel, note) {
+            Some(note) => {
+                exit_chord.contains(&note)
+                    && exit_chord
+                        .iter()
+                        .all(|held| *held == note || self.held.contains(held))
+            }
+            None => false,
+        }
+    }
+
+    // Chords are pressed by the note completing them, on top of that note's own mapping
+    fn press_chords(&mut self, channel: u8, note: u8, mappings: &Mappings) -> Vec<INPUT> {
+        let note = match mappings.transposed(channel, note) {
+            Some(note) => note,
+            None => return vec![],
+        };
+        self.held.insert(note);
+
+        let mut inputs = vec![];
+        for chord in mappings.chords() {
+            let complete = chord.notes.contains(&note)
+                && chord.notes.iter().all(|note| self.held.contains(note));
+            if complete && !self.chords.iter().any(|held| held.notes == chord.notes) {
+                inputs.extend(input::keys(&chord.keys, KEYDOWN));
+                self.chords.push(chord.clone());
+            }
+        }
+        inputs
+    }
+
+    // Releasing any note of a chord releases the whole chord
+    fn release_chords(&mut self, channel: u8, note: u8, mappings: &Mappings) -> Vec<INPUT> {
+        let note = match mappings.transposed(channel, note) {
+            Some(note) => note,
+            None => return vec![],
+        };
+        self.held.remove(&note);
+
+        let mut inputs = vec![];
+        self.chords.retain(|chord| {
+            let released = chord.notes.contains(&note);
+            if released {
+                inputs.extend(input::keys(&chord.keys, KEYEVENTF_KEYUP));
+            }
+            !released
+        });
+        inputs
+    }
+
+    // The keys go down with the first note and the other notes only add to the count
+    fn press_sustains(&mut self, channel: u8, note: u8, mappings: &Mappings) -> Vec<INPUT> {
+        let note = match mappings.transposed(channel, note) {
+            Some(note) => note,
+            None => return vec![],
+        };
+
+        let mut inputs = vec![];
+        for sustain in mappings.sustains() {
+            if !sustain.notes.contains(&note) {
+                continue;
+            }
+            match self
+                .sustains
+                .iter_mut()
+                .find(|(held, _)| held.notes == sustain.notes)
+            {
+                Some((_, count)) => *count += 1,
+                None => {
+                    inputs.extend(input::keys(&sustain.keys, KEYDOWN));
+                    self.sustains.push((sustain.clone(), 1));
+                }
+            }
+        }
+        inputs
+    }
+
+    // Held sustains are kept even if the mappings change so they still get released
+    fn release_sustains(&mut self, channel: u8, note: u8, mappings: &Mappings) -> Vec<INPUT> {
+        let note = match mappings.transposed(channel, note) {
+            Some(note) => note,
+            None => return vec![],
+        };
+
+        let mut inputs = vec![];
+        self.sustains.retain_mut(|(sustain, count)| {
+            if sustain.notes.contains(&note) {
+                *count = count.saturating_sub(1);
+            }
+            let released = *count == 0;
+            if released {
+                inputs.extend(input::keys(&sustain.keys, KEYEVENTF_KEYUP));
+            }
+            !released
+        });
+        inputs
+    }
+
+    // Works like the pitch wheel, with the threshold and hysteresis applied to
+    // the pressure. Held keys are kept even if the mappings change, and released
+    // by the next pressure once nothing maps them anymore.
+    fn press_pressure(
+        &mut self,
+        channel: u8,
+        note: Option<u8>,
+        pressure: u8,
+        mappings: &Mappings,
+    ) -> Vec<INPUT> {
+        let entries = || {
+            mappings
+                .aftertouch()
+                .iter()
+                .filter(|aftertouch| aftertouch.note == note)
+        };
+        let mut inputs = vec![];
+        self.pressing.retain(|(held_channel, held_note, keys)| {
+            let stale = (*held_channel, *held_note) == (channel, note)
+                && !entries().any(|aftertouch| aftertouch.hold.keys == *keys);
+            if stale {
+                inputs.extend(input::keys(keys, KEYEVENTF_KEYUP));
+            }
+            !stale
+        });
+
+        for aftertouch in entries() {
+            let held = self
+                .pressing
+                .iter()
+                .position(|(held_channel, held_note, keys)| {
+                    (*held_channel, *held_note) == (channel, note) && *keys == aftertouch.hold.keys
+                });
+            match (
+                aftertouch.hold.transition(held.is_some(), pressure.into()),
+                held,
+            ) {
+                (Some(true), _) => {
+                    self.pressing
+                        .push((channel, note, aftertouch.hold.keys.clone()));
+                    inputs.extend(input::keys(&aftertouch.hold.keys, KEYDOWN));
+                }
+                (Some(false), Some(index)) => {
+                    let (_, _, keys) = self.pressing.swap_remove(index);
+                    inputs.extend(input::keys(&keys, KEYEVENTF_KEYUP));
+                }
+                _ => {}
+            }
+        }
+        inputs
+    }
+
+    // Letting go of a note stops its polyphonic pressure, which some devices
+    // don't bother sending a last zero for
+    fn release_pressure(&mut self, channel: u8, note: u8, mappings: &Mappings) -> Vec<INPUT> {
+        let note = match mappings.transposed(channel, note) {
+            Some(note) => note,
+            None => return vec![],
+        };
+
+        let mut inputs = vec![];
+        self.pressing.retain(|(held_channel, held_note, keys)| {
+            let released = (*held_channel, *held_note) == (channel, Some(note));
+            if released {
+                inputs.extend(input::keys(keys, KEYEVENTF_KEYUP));
+            }
+            !released
+        });
+        inputs
+    }
+
+    // Forgets about every held note and releases everything still held down,
+    // whether or not it was the handler that pressed it
+    pub fn reset(&mut self) -> Vec<INPUT> {
+        self.repeats.stop_all();
+        self.macros.stop_all();
+        for (cancelled, _) in self.delayed.values() {
+            cancelled.store(true, Ordering::Release);
+        }
+        self.delayed.clear();
+        self.down.clear();
+        self.debounced.clear();
+        self.pressed = Pressed::new();
+        self.holds.clear();
+        self.latched.clear();
+        self.inverted.clear();
+        self.raised.clear();
+        self.held.clear();
+        self.chords.clear();
+        self.sustains.clear();
+        self.modifiers.clear();
+        self.buckets.clear();
+        self.pairs.clear();
+        self.pressing.clear();
+        self.bent = [None, None];
+        input::release_all_inputs()
+    }
+
+    // Undoes whatever the note on did
+    fn release_note(&mut self, channel: u8, note: u8) -> Vec<INPUT> {
+        let action = self.pressed.slot(channel, note).take();
+        if let Some(Action::Layer(_)) = action {
+            self.modifiers.retain(|&(held_channel, held_note, _)| {
+                (held_channel, held_note) != (channel, note)
+            });
+            return vec![];
+        }
+        if let Some(Action::NoteOut(out)) = action {
+            self.holds.remove(&(channel, note));
+            self.send_note(channel, out, None);
+            return vec![];
+        }
+        if self.repeats.stop(channel, note) {
+            return vec![];
+        }
+        let action = match action {
+            Some(action) => action,
+            None => return vec![],
+        };
+
+        let now = Instant::now();
+        match self.holds.remove(&(channel, note)) {
+            Some(deadline) if deadline > now => {
+                let cancelled = Arc::new(AtomicBool::new(false));
+                release_later(
+                    action.clone(),
+                    deadline - now,
+                    self.repeats.tag(),
+                    cancelled.clone(),
+                );
+                self.delayed.insert((channel, note), (cancelled, action));
+                vec![]
+            }
+            _ => input::release(&action),
+        }
+    }
+
+    // Note on with the velocity of the incoming one, note off without
+    fn send_note(&mut self, channel: u8, note: u8, velocity: Option<u8>) {
+        self.remapped = true;
+        let message = match velocity {
+            Some(velocity) => MidiNoteOnMessage::CreateMidiNoteOnMessage(channel, note, velocity)
+                .and_then(|message| message.cast()),
+            None => MidiNoteOffMessage::CreateMidiNoteOffMessage(channel, note, 0)
+                .and_then(|message| message.cast()),
+        };
+        match message {
+            Ok(message) => self.send_out(&message),
+            Err(error) => report_error(error),
+        }
+    }
+
+    // Mapping goes on regardless so a missing output doesn't flood the terminal
+    fn send_out(&mut self, message: &IMidiMessage) {
+        let forward = match &self.forward {
+            Some(forward) => forward,
+            None => return,
+        };
+        if let (Err(error), false) = (forward.SendMessage(message), self.forward_failed) {
+            self.forward_failed = true;
+            report_error(error);
+        }
+    }
+
+    // A note played again before its delayed release is released right away
+    fn release_delayed(&mut self, channel: u8, note: u8) -> Vec<INPUT> {
+        match self.delayed.remove(&(channel, note)) {
+            Some((cancelled, action)) if !cancelled.swap(true, Ordering::AcqRel) => {
+                input::release(&action)
+            }
+            _ => vec![],
+        }
+    }
+}
+
+// Ignored message types and messages on disabled channels are dropped before
+// anything looks at them. Note offs still go through so notes held when a reload
+// disabled the channel aren't left down.
+fn accepts(event: &Event, mappings: &Mappings) -> bool {
+    let disabled = event
+        .channel()
+        .is_some_and(|channel| !mappings.enabled(channel));
+    !mappings.ignores(event.ty()) && (!disabled || matches!(event, Event::NoteOff { .. }))
+}
+
+fn release_later(action: Action, delay: Duration, tag: Tag, cancelled: Arc<AtomicBool>) {
+    schedule::after(
+        delay,
+        tag,
+        Job::new(move |_| {
+            if !cancelled.swap(true, Ordering::AcqRel) {
+                input::send(input::release(&action));
+            }
+            None
+        }),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use windows::Win32::UI::Input::KeyboardAndMouse::{
+        VK_F1, VK_F10, VK_F11, VK_F12, VK_F2, VK_F3, VK_F4, VK_F5, VK_F6, VK_F7, VK_F8, VK_F9,
+    };
+
+    use super::*;
+
+    fn handler() -> Handler {
+        let repeats = Arc::new(Repeats::default());
+        let macros = Arc::new(Repeats::default());
+        Handler::new(repeats, macros, false, false, None, None, None)
+    }
+
+    fn mappings(source: &str) -> Mappings {
+        source.parse().unwrap()
+    }
+
+    fn on(note: u8, velocity: u8) -> Event {
+        Event::NoteOn {
+            channel: 0,
+            note,
+            velocity,
+        }
+    }
+
+    fn off(note: u8) -> Event {
+        Event::NoteOff { channel: 0, note }
+    }
+
+    // Each key with whether it goes down
+    fn keys(inputs: Vec<INPUT>) -> Vec<(VIRTUAL_KEY, bool)> {
+        inputs
+            .iter()
+            .map(|input| {
+                let ki = unsafe { input.Anonymous.ki };
+                (ki.wVk, ki.dwFlags.0 & KEYEVENTF_KEYUP.0 == 0)
+            })
+            .collect()
+    }
+
+    fn replay(
+        handler: &mut Handler,
+        event: Event,
+        mappings: &Mappings,
+    ) -> Vec<(VIRTUAL_KEY, bool)> {
+        keys(handler.replay([event], mappings))
+    }
+
+    #[test]
+    fn note_on_without_velocity_decodes_as_note_off() {
+        let message: IMidiMessage = MidiNoteOnMessage::CreateMidiNoteOnMessage(0, 60, 0)
+            .unwrap()
+            .cast()
+            .unwrap();
+        assert!(matches!(
+            Event::decode(&message),
+            Ok(Some(Event::NoteOff {
+                channel: 0,
+                note: 60
+            })),
+        ));
+    }
+
+    #[test]
+    fn velocity_windows() {
+        let mappings = mappings(
+            r#"
+            [[mapping]]
+            note = 60
+            key = "f1"
+            velocity_max = 63
+
+            [[mapping]]
+            note = 60
+            key = "f2"
+            velocity_min = 64
+            "#,
+        );
+        let mut handler = handler();
+
+        assert_eq!(replay(&mut handler, on(60, 20), &mappings), [(VK_F1, true)]);
+        assert_eq!(replay(&mut handler, off(60), &mappings), [(VK_F1, false)]);
+        assert_eq!(
+            replay(&mut handler, on(60, 100), &mappings),
+            [(VK_F2, true)]
+        );
+        assert_eq!(replay(&mut handler, off(60), &mappings), [(VK_F2, false)]);
+    }
+
+    #[test]
+    fn program_change_switches_layer() {
+        let mappings = mappings(
+            r#"
+            [[mapping]]
+            note = 60
+            key = "f1"
+
+            [[layer]]
+            program = 2
+            name = "other"
+
+            [[layer.mapping]]
+            note = 60
+            key = "f2"
+            "#,
+        );
+        let mut handler = handler();
+
+        let program = Event::ProgramChange {
+            channel: 0,
+            program: 1,
+        };
+        assert!(replay(&mut handler, program, &mappings).is_empty());
+        assert_eq!(
+            replay(&mut handler, on(60, 100), &mappings),
+            [(VK_F2, true)]
+        );
+        assert_eq!(replay(&mut handler, off(60), &mappings), [(VK_F2, false)]);
+    }
+
+    #[test]
+    fn aftertouch_released_after_layer_switch() {
+        let mappings = mappings(
+            r#"
+            [[aftertouch]]
+            threshold = 64
+            key = "f8"
+
+            [[layer]]
+            program = 2
+            name = "other"
+
+            [[layer.aftertouch]]
+            threshold = 64
+            key = "f9"
+            "#,
+        );
+        let mut handler = handler();
+        let pressure = |pressure| Event::ChannelPressure {
+            channel: 0,
+            pressure,
+        };
+        let program = Event::ProgramChange {
+            channel: 0,
+            program: 1,
+        };
+
+        assert_eq!(
+            replay(&mut handler, pressure(100), &mappings),
+            [(VK_F8, true)]
+        );
+        assert!(replay(&mut handler, program, &mappings).is_empty());
+        assert_eq!(
+            replay(&mut handler, pressure(100), &mappings),
+            [(VK_F8, false), (VK_F9, true)]
+        );
+        assert_eq!(
+            replay(&mut handler, pressure(0), &mappings),
+            [(VK_F9, false)]
+        );
+    }
+
+    #[test]
+    fn pitch_bend_released_after_layer_switch() {
+        let mappings = mappings(
+            r#"
+            [pitchbend]
+            up = { threshold = 12288, key = "f10" }
+
+            [[layer]]
+            program = 2
+            name = "other"
+
+            [layer.pitchbend]
+            up = { threshold = 12288, key = "f11" }
+            "#,
+        );
+        let mut handler = handler();
+        let bend = |value| Event::PitchBend { channel: 0, value };
+        let program = Event::ProgramChange {
+            channel: 0,
+            program: 1,
+        };
+
+        assert_eq!(
+            replay(&mut handler, bend(16000), &mappings),
+            [(VK_F10, true)]
+        );
+        assert!(replay(&mut handler, program, &mappings).is_empty());
+        assert_eq!(
+            replay(&mut handler, bend(16000), &mappings),
+            [(VK_F10, false), (VK_F11, true)]
+        );
+        assert_eq!(
+            replay(&mut handler, bend(8192), &mappings),
+            [(VK_F11, false)]
+        );
+    }
+
+    #[test]
+    fn modifier_layer_while_held() {
+        let mappings = mappings(
+            r#"
+            [[mapping]]
+            note = 59
+            modifier_layer = "held"
+
+            [[mapping]]
+            note = 60
+            key = "f1"
+
+            [[layer]]
+            name = "held"
+
+            [[layer.mapping]]
+            note = 60
+            key = "f2"
+            "#,
+        );
+        let mut handler = handler();
+
+        assert!(replay(&mut handler, on(59, 100), &mappings).is_empty());
+        assert_eq!(
+            replay(&mut handler, on(60, 100), &mappings),
+            [(VK_F2, true)]
+        );
+        assert_eq!(replay(&mut handler, off(60), &mappings), [(VK_F2, false)]);
+        assert!(replay(&mut handler, off(59), &mappings).is_empty());
+        assert_eq!(
+            replay(&mut handler, on(60, 100), &mappings),
+            [(VK_F1, true)]
+        );
+    }
+
+    #[test]
+    fn chord_released_by_any_note() {
+        let mappings = mappings(
+            r#"
+            [[mapping]]
+            note = 60
+            key = "f1"
+
+            [[mapping]]
+            note = 64
+            key = "f2"
+
+            [[chord]]
+            notes = [60, 64]
+            key = "f3"
+            "#,
+        );
+        let mut handler = handler();
+
+        assert_eq!(
+            replay(&mut handler, on(60, 100), &mappings),
+            [(VK_F1, true)]
+        );
+        assert_eq!(
+            replay(&mut handler, on(64, 100), &mappings),
+            [(VK_F2, true), (VK_F3, true)],
+        );
+        assert_eq!(
+            replay(&mut handler, off(60), &mappings),
+            [(VK_F3, false), (VK_F1, false)],
+        );
+        assert_eq!(replay(&mut handler, off(64), &mappings), [(VK_F2, false)]);
+    }
+
+    #[test]
+    fn sustain_held_until_last_note() {
+        let mappings = mappings(
+            r#"
+            [[sustain]]
+            notes = [60, 62]
+            key = "f4"
+            "#,
+        );
+        let mut handler = handler();
+
+        assert_eq!(
+            replay(&mut handler, on(60, 100), &mappings),
+            [(VK_F4, true)]
+        );
+        assert!(replay(&mut handler, on(62, 100), &mappings).is_empty());
+        assert!(replay(&mut handler, off(60), &mappings).is_empty());
+        assert_eq!(replay(&mut handler, off(62), &mappings), [(VK_F4, false)]);
+    }
+
+    #[test]
+    fn debounced_note_leaves_sustain_held() {
+        let mappings = mappings(
+            r#"
+            debounce_ms = 60000
+
+            [[sustain]]
+            notes = [60, 62]
+            key = "f7"
+            "#,
+        );
+        let mut handler = handler();
+
+        assert_eq!(
+            replay(&mut handler, on(60, 100), &mappings),
+            [(VK_F7, true)]
+        );
+        assert_eq!(replay(&mut handler, off(60), &mappings), [(VK_F7, false)]);
+        assert_eq!(
+            replay(&mut handler, on(62, 100), &mappings),
+            [(VK_F7, true)]
+        );
+        assert!(replay(&mut handler, on(60, 100), &mappings).is_empty());
+        assert!(replay(&mut handler, off(60), &mappings).is_empty());
+        assert_eq!(replay(&mut handler, off(62), &mappings), [(VK_F7, false)]);
+    }
+
+    #[test]
+    fn toggle_latches_until_next_note_on() {
+        let mappings = mappings(
+            r#"
+            [[mapping]]
+            note = 72
+            key = "f5"
+            toggle = true
+            "#,
+        );
+        let mut handler = handler();
+
+        assert_eq!(
+            replay(&mut handler, on(72, 100), &mappings),
+            [(VK_F5, true)]
+        );
+        assert!(replay(&mut handler, off(72), &mappings).is_empty());
+        assert_eq!(
+            replay(&mut handler, on(72, 100), &mappings),
+            [(VK_F5, false)]
+        );
+        assert!(replay(&mut handler, off(72), &mappings).is_empty());
+    }
+
+    #[test]
+    fn toggle_without_note_offs() {
+        let mappings = mappings(
+            r#"
+            ignore = ["noteoff"]
+
+            [[mapping]]
+            note = 74
+            key = "f6"
+            toggle = true
+            "#,
+        );
+        let mut handler = handler();
+
+        // Dropped note offs don't leave later note ons counted as retriggers
+        for _ in 0..2 {
+            assert_eq!(
+                replay(&mut handler, on(74, 100), &mappings),
+                [(VK_F6, true)]
+            );
+            assert!(replay(&mut handler, off(74), &mappings).is_empty());
+            assert_eq!(
+                replay(&mut handler, on(74, 100), &mappings),
+                [(VK_F6, false)]
+            );
+            assert!(replay(&mut handler, off(74), &mappings).is_empty());
+        }
+    }
+
+    #[test]
+    fn invert_presses_on_note_off() {
+        let mappings = mappings(
+            r#"
+            [[mapping]]
+            note = 78
+            key = "f12"
+            invert = true
+            "#,
+        );
+        let mut handler = handler();
+
+        assert!(replay(&mut handler, on(78, 100), &mappings).is_empty());
+        for _ in 0..2 {
+            assert_eq!(replay(&mut handler, off(78), &mappings), [(VK_F12, true)]);
+            assert_eq!(
+                replay(&mut handler, on(78, 100), &mappings),
+                [(VK_F12, false)]
+            );
+        }
+    }
+
+    #[test]
+    fn retriggered_note_on_counted() {
+        let mappings = mappings(
+            r#"
+            [[mapping]]
+            note = 60
+            key = "f1"
+            "#,
+        );
+        let mut handler = handler();
+
+        assert_eq!(
+            replay(&mut handler, on(60, 100), &mappings),
+            [(VK_F1, true)]
+        );
+        assert!(replay(&mut handler, on(60, 100), &mappings).is_empty());
+        assert!(replay(&mut handler, off(60), &mappings).is_empty());
+        assert_eq!(replay(&mut handler, off(60), &mappings), [(VK_F1, false)]);
+    }
+}
@@ -0,0 +1,489 @@
+use std::{
+    collections::BTreeMap,
+    mem::size_of,
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use windows::Win32::{
+    Foundation::E_ACCESSDENIED,
+    UI::{
+        Input::KeyboardAndMouse::{
+            SendInput, INPUT, INPUT_0, INPUT_KEYBOARD, INPUT_MOUSE, KEYBDINPUT, KEYBD_EVENT_FLAGS,
+            KEYEVENTF_EXTENDEDKEY, KEYEVENTF_KEYUP, KEYEVENTF_SCANCODE, KEYEVENTF_UNICODE,
+            MOUSEEVENTF_LEFTDOWN, MOUSEEVENTF_LEFTUP, MOUSEEVENTF_MIDDLEDOWN, MOUSEEVENTF_MIDDLEUP,
+            MOUSEEVENTF_MOVE, MOUSEEVENTF_RIGHTDOWN, MOUSEEVENTF_RIGHTUP, MOUSEINPUT,
+            MOUSE_EVENT_FLAGS, VIRTUAL_KEY, VK_NONAME,
+        },
+        WindowsAndMessaging::GetMessageExtraInfo,
+    },
+};
+
+use crate::{
+    config::{Mouse, MouseButton},
+    mappings::Action,
+    report_error, Error,
+};
+
+pub const KEYDOWN: KEYBD_EVENT_FLAGS = KEYBD_EVENT_FLAGS(0);
+
+// Keys and buttons currently held down by us, so they can be released if their
+// note off never comes
+static HELD: Mutex<BTreeMap<Held, Hold>> = Mutex::new(BTreeMap::new());
+
+// Inputs are printed instead of sent when set
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+// Set once the user has been told about input blocked by UIPI
+static BLOCKED: AtomicBool = AtomicBool::new(false);
+
+// How many times SendInput is tried on a batch it only sends part of
+const SEND_ATTEMPTS: usize = 4;
+
+// Inputs handed to SendInput and how many of them didn't go through, only the
+// first failure is reported and the rest are summed up when exiting
+static SENT: AtomicUsize = AtomicUsize::new(0);
+static FAILED: AtomicUsize = AtomicUsize::new(0);
+
+// How many times it was pressed without being released, so a key several notes
+// are mapped to only goes up with the last of them, and since when. Latched
+// ones have no time since they're meant to stay down.
+struct Hold {
+    count: usize,
+    since: Option<Instant>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Held {
+    Key(u16),
+    // Scan codes as written in the config, with 0xE0 in front for extended keys
+    Scan(u16),
+    Button(MouseButton),
+}
+
+pub fn press(action: &Action) -> Vec<INPUT> {
+    match action {
+        Action::Keys(keys) => self::keys(keys, KEYDOWN),
+        Action::ScanCodes(codes) => scancodes(codes, KEYDOWN),
+        Action::Tap(keys) => keys.iter().flat_map(|key| tap(&[*key])).collect(),
+        Action::Mouse(Mouse::Button(button)) => vec![mouse((0, 0), button_flags(*button).0)],
+        Action::Mouse(Mouse::Move { by }) => vec![mouse(*by, MOUSEEVENTF_MOVE)],
+        Action::Text(text) => self::text(text),
+        Action::Exec(_) | Action::Macro(_) | Action::NoteOut(_) | Action::Layer(_) => vec![],
+    }
+}
+
+// Mouse moves, taps and text only happen on press, and programs, macros and
+// layers don't send anything
+pub fn release(action: &Action) -> Vec<INPUT> {
+    match action {
+        Action::Keys(keys) => self::keys(keys, KEYEVENTF_KEYUP),
+        Action::ScanCodes(codes) => scancodes(codes, KEYEVENTF_KEYUP),
+        Action::Mouse(Mouse::Button(button)) => vec![mouse((0, 0), button_flags(*button).1)],
+        Action::Mouse(Mouse::Move { .. })
+        | Action::Tap(_)
+        | Action::Text(_)
+        | Action::Exec(_)
+        | Action::Macro(_)
+        | Action::NoteOut(_)
+        | Action::Layer(_) => vec![],
+    }
+}
+
+fn button_flags(button: MouseButton) -> (MOUSE_EVENT_FLAGS, MOUSE_EVENT_FLAGS) {
+    match button {
+        MouseButton::Left => (MOUSEEVENTF_LEFTDOWN, MOUSEEVENTF_LEFTUP),
+        MouseButton::Right => (MOUSEEVENTF_RIGHTDOWN, MOUSEEVENTF_RIGHTUP),
+        MouseButton::Middle => (MOUSEEVENTF_MIDDLEDOWN, MOUSEEVENTF_MIDDLEUP),
+    }
+}
+
+// Keys are pressed in order and released in reverse so modifiers wrap the main key
+pub fn keys(keys: &[VIRTUAL_KEY], ty: KEYBD_EVENT_FLAGS) -> Vec<INPUT> {
+    if ty == KEYEVENTF_KEYUP {
+        keys.iter().rev().map(|&key| keyboard(key, 0, ty)).collect()
+    } else {
+        keys.iter().map(|&key| keyboard(key, 0, ty)).collect()
+    }
+}
+
+// Same order as keys, but without a virtual key so the game sees the hardware key
+fn scancodes(codes: &[u16], ty: KEYBD_EVENT_FLAGS) -> Vec<INPUT> {
+    if ty == KEYEVENTF_KEYUP {
+        codes.iter().rev().map(|&code| scancode(code, ty)).collect()
+    } else {
+        codes.iter().map(|&code| scancode(code, ty)).collect()
+    }
+}
+
+fn scancode(code: u16, ty: KEYBD_EVENT_FLAGS) -> INPUT {
+    let mut flags = ty | KEYEVENTF_SCANCODE;
+    if code >> 8 == 0xE0 {
+        flags |= KEYEVENTF_EXTENDEDKEY;
+    }
+    keyboard(VIRTUAL_KEY(0), code & 0xFF, flags)
+}
+
+pub fn tap(keys: &[VIRTUAL_KEY]) -> Vec<INPUT> {
+    let mut inputs = self::keys(keys, KEYDOWN);
+    inputs.extend(self::keys(keys, KEYEVENTF_KEYUP));
+    inputs
+}
+
+// Each UTF-16 code unit is typed as is without going through the keyboard layout
+fn text(text: &str) -> Vec<INPUT> {
+    text.encode_utf16()
+        .flat_map(|unit| {
+            [
+                keyboard(VIRTUAL_KEY(0), unit, KEYEVENTF_UNICODE),
+                keyboard(VIRTUAL_KEY(0), unit, KEYEVENTF_UNICODE | KEYEVENTF_KEYUP),
+            ]
+        })
+        .collect()
+}
+
+fn keyboard(key: VIRTUAL_KEY, scan: u16, flags: KEYBD_EVENT_FLAGS) -> INPUT {
+    INPUT {
+        r#type: INPUT_KEYBOARD,
+        Anonymous: INPUT_0 {
+            ki: KEYBDINPUT {
+                wVk: key,
+                wScan: scan,
+                dwFlags: flags,
+                time: 0,
+                dwExtraInfo: 0,
+            },
+        },
+    }
+}
+
+// Mouse moves are relative to the current cursor position
+fn mouse((dx, dy): (i32, i32), flags: MOUSE_EVENT_FLAGS) -> INPUT {
+    INPUT {
+        r#type: INPUT_MOUSE,
+        Anonymous: INPUT_0 {
+            mi: MOUSEINPUT {
+                dx,
+                dy,
+                mouseData: 0,
+                dwFlags: flags,
+                time: 0,
+                dwExtraInfo: 0,
+            },
+        },
+    }
+}
+
+// Everything is sent in a single batch which other input can't be interleaved
+// with. Failures are counted and only the first one is reported, so there's
+// nothing for callers to handle. Returns how many inputs went through, leaving
+// out releases of keys something else still holds.
+pub fn send(mut inputs: Vec<INPUT>) -> usize {
+    if inputs.is_empty() {
+        return 0;
+    }
+
+    let extra_info = unsafe { GetMessageExtraInfo().0 as usize };
+    let now = Instant::now();
+    let mut held = vec![];
+    for (index, input) in inputs.iter_mut().enumerate() {
+        match input.r#type {
+            INPUT_KEYBOARD => {
+                input.Anonymous.ki.dwExtraInfo = extra_info;
+                // Unicode text is typed as a packet which isn't a held key
+                let KEYBDINPUT {
+                    wVk,
+                    wScan,
+                    dwFlags,
+                    ..
+                } = unsafe { input.Anonymous.ki };
+                let down = !dwFlags.contains(KEYEVENTF_KEYUP);
+                if dwFlags.contains(KEYEVENTF_SCANCODE) {
+                    held.push((index, Held::Scan(scan_code(wScan, dwFlags)), down));
+                } else if !dwFlags.contains(KEYEVENTF_UNICODE) {
+                    held.push((index, Held::Key(wVk.0), down));
+                }
+            }
+            INPUT_MOUSE => {
+                input.Anonymous.mi.dwExtraInfo = extra_info;
+                let flags = unsafe { input.Anonymous.mi.dwFlags };
+                for button in [MouseButton::Left, MouseButton::Right, MouseButton::Middle] {
+                    let (down, up) = button_flags(button);
+                    if flags.contains(down) {
+                        held.push((index, Held::Button(button), true));
+                    } else if flags.contains(up) {
+                        held.push((index, Held::Button(button), false));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Counted before sending so releases of keys something else still holds
+    // can be left out, and undone below for what doesn't go through
+    let mut skipped = vec![];
+    {
+        let mut held_down = HELD.lock().unwrap();
+        for &(index, held, down) in &held {
+            if down {
+                let hold = held_down.entry(held).or_insert(Hold {
+                    count: 0,
+                    since: Some(now),
+                });
+                hold.count += 1;
+                continue;
+            }
+            match held_down.get_mut(&held) {
+                Some(hold) if hold.count > 1 => {
+                    hold.count -= 1;
+                    skipped.push(index);
+                }
+                _ => {
+                    held_down.remove(&held);
+                }
+            }
+        }
+    }
+    // Indices into what's left
+    let held: Vec<(usize, Held, bool)> = held
+        .into_iter()
+        .filter(|(index, ..)| !skipped.contains(index))
+        .map(|(index, held, down)| {
+            let before = skipped.iter().filter(|&&skip| skip < index).count();
+            (index - before, held, down)
+        })
+        .collect();
+    let inputs: Vec<INPUT> = inputs
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| !skipped.contains(index))
+        .map(|(_, input)| input)
+        .collect();
+    if inputs.is_empty() {
+        return 0;
+    }
+
+    let mut sent = inputs.len();
+    if DRY_RUN.load(Ordering::Relaxed) {
+        for input in &inputs {
+            println!("would send {}", describe(input));
+        }
+    } else {
+        SENT.fetch_add(inputs.len(), Ordering::Relaxed);
+        if let Err((done, error)) = send_all(&inputs) {
+            sent = done;
+            let first = FAILED.fetch_add(inputs.len() - sent, Ordering::Relaxed) == 0;
+            // UIPI drops input to elevated windows, which would otherwise fail
+            // on every single note until focus moves elsewhere
+            if sent == 0 && error.code() == E_ACCESSDENIED {
+                if !BLOCKED.swap(true, Ordering::Relaxed) {
+                    report_error(Error::InputBlocked);
+                }
+            } else if first {
+                report_error(error);
+            }
+        }
+    }
+
+    // Only what actually went through stays tracked so it can still be released
+    let mut held_down = HELD.lock().unwrap();
+    for (_, held, down) in held.into_iter().rev().filter(|&(index, ..)| index >= sent) {
+        if down {
+            if let Some(hold) = held_down.get_mut(&held) {
+                hold.count -= 1;
+                if hold.count == 0 {
+                    held_down.remove(&held);
+                }
+            }
+        } else {
+            held_down.entry(held).or_insert(Hold {
+                count: 1,
+                since: Some(now),
+            });
+        }
+    }
+    sent
+}
+
+// SendInput stops partway through a batch when something blocks it, like focus
+// changing, so the rest is sent again for as long as each attempt gets further.
+// Returns how much was sent when it gives up.
+fn send_all(inputs: &[INPUT]) -> Result<(), (usize, windows::core::Error)> {
+    let mut done = 0;
+    for attempt in 1.. {
+        let sent = unsafe { SendInput(&inputs[done..], size_of::<INPUT>() as i32) } as usize;
+        done += sent;
+        if done >= inputs.len() {
+            break;
+        }
+        if sent == 0 || attempt == SEND_ATTEMPTS {
+            return Err((done, windows::core::Error::from_win32()));
+        }
+    }
+    Ok(())
+}
+
+// Taps a reserved key nothing reacts to, outside of what's tracked and counted,
+// so a blocked SendInput is found out before playing instead of while playing
+pub fn self_test() -> Result<(), Error> {
+    let inputs = [
+        keyboard(VK_NONAME, 0, KEYDOWN),
+        keyboard(VK_NONAME, 0, KEYEVENTF_KEYUP),
+    ];
+    match send_all(&inputs) {
+        Ok(()) => Ok(()),
+        Err((_, error)) => Err(Error::SelfTest(error)),
+    }
+}
+
+// The program isn't waited on so it can't hold up the messages coming after
+pub fn exec(command: &[String]) {
+    let (program, args) = match command {
+        [program, args @ ..] => (program, args),
+        [] => return,
+    };
+    if DRY_RUN.load(Ordering::Relaxed) {
+        println!("would run {}", command.join(" "));
+        return;
+    }
+    if let Err(error) = Command::new(program).args(args).spawn() {
+        report_error(error);
+    }
+}
+
+// How many inputs failed out of how many were sent
+pub fn failures() -> (usize, usize) {
+    (FAILED.load(Ordering::Relaxed), SENT.load(Ordering::Relaxed))
+}
+
+pub fn set_dry_run(dry_run: bool) {
+    DRY_RUN.store(dry_run, Ordering::Relaxed);
+}
+
+// Back to the config form of a scan code input
+fn scan_code(scan: u16, flags: KEYBD_EVENT_FLAGS) -> u16 {
+    if flags.contains(KEYEVENTF_EXTENDEDKEY) {
+        0xE000 | scan
+    } else {
+        scan
+    }
+}
+
+fn describe(input: &INPUT) -> String {
+    match input.r#type {
+        INPUT_KEYBOARD => {
+            let KEYBDINPUT {
+                wVk,
+                wScan,
+                dwFlags,
+                ..
+            } = unsafe { input.Anonymous.ki };
+            let direction = if dwFlags.contains(KEYEVENTF_KEYUP) {
+                "up"
+            } else {
+                "down"
+            };
+            if dwFlags.contains(KEYEVENTF_UNICODE) {
+                format!("U+{wScan:04X} {direction}")
+            } else if dwFlags.contains(KEYEVENTF_SCANCODE) {
+                format!("scancode {:#04X} {direction}", scan_code(wScan, dwFlags))
+            } else {
+                format!("VK {:#04X} {direction}", wVk.0)
+            }
+        }
+        INPUT_MOUSE => {
+            let MOUSEINPUT {
+                dx, dy, dwFlags, ..
+            } = unsafe { input.Anonymous.mi };
+            if dwFlags.contains(MOUSEEVENTF_MOVE) {
+                return format!("mouse move by {dx}, {dy}");
+            }
+            for button in [MouseButton::Left, MouseButton::Right, MouseButton::Middle] {
+                let (down, up) = button_flags(button);
+                let name = match button {
+                    MouseButton::Left => "left",
+                    MouseButton::Right => "right",
+                    MouseButton::Middle => "middle",
+                };
+                if dwFlags.contains(down) {
+                    return format!("{name} mouse button down");
+                } else if dwFlags.contains(up) {
+                    return format!("{name} mouse button up");
+                }
+            }
+            "mouse input".to_owned()
+        }
+        _ => "unknown input".to_owned(),
+    }
+}
+
+// Marks what the action is about to hold as latched so it isn't released for
+// being held too long
+pub fn latch(action: &Action) {
+    let held: Vec<Held> = match action {
+        Action::Keys(keys) => keys.iter().map(|key| Held::Key(key.0)).collect(),
+        Action::ScanCodes(codes) => codes.iter().map(|&code| Held::Scan(code)).collect(),
+        Action::Mouse(Mouse::Button(button)) => vec![Held::Button(*button)],
+        Action::Mouse(Mouse::Move { .. })
+        | Action::Tap(_)
+        | Action::Text(_)
+        | Action::Exec(_)
+        | Action::Macro(_)
+        | Action::NoteOut(_)
+        | Action::Layer(_) => vec![],
+    };
+    let mut held_down = HELD.lock().unwrap();
+    for held in held {
+        held_down
+            .entry(held)
+            .or_insert(Hold {
+                count: 0,
+                since: None,
+            })
+            .since = None;
+    }
+}
+
+// Whether the key is down, from the handler or anything else releasing it
+pub fn held(key: VIRTUAL_KEY) -> bool {
+    HELD.lock().unwrap().contains_key(&Held::Key(key.0))
+}
+
+pub fn release_all() {
+    send(releases(|_| true));
+}
+
+// What release_all sends, for sending along with other inputs
+pub fn release_all_inputs() -> Vec<INPUT> {
+    releases(|_| true)
+}
+
+pub fn release_stale(timeout: Duration) {
+    send(releases(|since| {
+        since.is_some_and(|since| since.elapsed() >= timeout)
+    }));
+}
+
+// Whatever held them, so they're counted as held once for the release to go
+// through
+fn releases(stale: impl Fn(Option<Instant>) -> bool) -> Vec<INPUT> {
+    HELD.lock()
+        .unwrap()
+        .iter_mut()
+        .filter(|(_, hold)| stale(hold.since))
+        .map(|(&held, hold)| {
+            hold.count = 1;
+            held
+        })
+        .map(|held| match held {
+            Held::Key(key) => keyboard(VIRTUAL_KEY(key), 0, KEYEVENTF_KEYUP),
+            Held::Scan(code) => scancode(code, KEYEVENTF_KEYUP),
+            Held::Button(button) => mouse((0, 0), button_flags(button).1),
+        })
+        .collect()
+}
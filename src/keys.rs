@@ -0,0 +1,250 @@
+use std::sync::atomic::{AtomicBool, AtomicIsize, Ordering};
+
+use windows::{
+    core::HSTRING,
+    Win32::UI::{
+        Input::KeyboardAndMouse::{
+            GetKeyboardLayout, LoadKeyboardLayoutW, VkKeyScanExW, KLF_NOTELLSHELL, VIRTUAL_KEY,
+            VK_ADD, VK_APPS, VK_BACK, VK_CAPITAL, VK_CONTROL, VK_DECIMAL, VK_DELETE, VK_DIVIDE,
+            VK_DOWN, VK_END, VK_ESCAPE, VK_F1, VK_HOME, VK_INSERT, VK_LCONTROL, VK_LEFT, VK_LMENU,
+            VK_LSHIFT, VK_LWIN, VK_MEDIA_NEXT_TRACK, VK_MEDIA_PLAY_PAUSE, VK_MEDIA_PREV_TRACK,
+            VK_MENU, VK_MULTIPLY, VK_NEXT, VK_NUMLOCK, VK_NUMPAD0, VK_OEM_1, VK_OEM_2, VK_OEM_3,
+            VK_OEM_4, VK_OEM_5, VK_OEM_6, VK_OEM_7, VK_OEM_COMMA, VK_OEM_MINUS, VK_OEM_PERIOD,
+            VK_OEM_PLUS, VK_PAUSE, VK_PRIOR, VK_RCONTROL, VK_RETURN, VK_RIGHT, VK_RMENU, VK_RSHIFT,
+            VK_RWIN, VK_SCROLL, VK_SHIFT, VK_SNAPSHOT, VK_SPACE, VK_SUBTRACT, VK_TAB, VK_UP,
+            VK_VOLUME_DOWN, VK_VOLUME_MUTE, VK_VOLUME_UP,
+        },
+        TextServices::HKL,
+    },
+};
+
+// https://learn.microsoft.com/en-us/windows/win32/inputdev/virtual-key-codes
+const NAMED_KEYS: &[(&str, VIRTUAL_KEY)] = &[
+    ("space", VK_SPACE),
+    ("enter", VK_RETURN),
+    ("return", VK_RETURN),
+    ("tab", VK_TAB),
+    ("escape", VK_ESCAPE),
+    ("esc", VK_ESCAPE),
+    ("backspace", VK_BACK),
+    ("delete", VK_DELETE),
+    ("del", VK_DELETE),
+    ("insert", VK_INSERT),
+    ("home", VK_HOME),
+    ("end", VK_END),
+    ("pageup", VK_PRIOR),
+    ("pagedown", VK_NEXT),
+    ("up", VK_UP),
+    ("down", VK_DOWN),
+    ("left", VK_LEFT),
+    ("right", VK_RIGHT),
+    ("ctrl", VK_CONTROL),
+    ("control", VK_CONTROL),
+    ("shift", VK_SHIFT),
+    ("alt", VK_MENU),
+    ("win", VK_LWIN),
+    ("lctrl", VK_LCONTROL),
+    ("rctrl", VK_RCONTROL),
+    ("lshift", VK_LSHIFT),
+    ("rshift", VK_RSHIFT),
+    ("lalt", VK_LMENU),
+    ("ralt", VK_RMENU),
+    ("lwin", VK_LWIN),
+    ("rwin", VK_RWIN),
+    ("menu", VK_APPS),
+    ("capslock", VK_CAPITAL),
+    ("numlock", VK_NUMLOCK),
+    ("scrolllock", VK_SCROLL),
+    ("printscreen", VK_SNAPSHOT),
+    ("pause", VK_PAUSE),
+    ("plus", VK_OEM_PLUS),
+    ("minus", VK_OEM_MINUS),
+    ("comma", VK_OEM_COMMA),
+    ("period", VK_OEM_PERIOD),
+    ("multiply", VK_MULTIPLY),
+    ("add", VK_ADD),
+    ("subtract", VK_SUBTRACT),
+    ("decimal", VK_DECIMAL),
+    ("divide", VK_DIVIDE),
+    ("volumeup", VK_VOLUME_UP),
+    ("volumedown", VK_VOLUME_DOWN),
+    ("mute", VK_VOLUME_MUTE),
+    ("playpause", VK_MEDIA_PLAY_PAUSE),
+    ("nexttrack", VK_MEDIA_NEXT_TRACK),
+    ("prevtrack", VK_MEDIA_PREV_TRACK),
+];
+
+// Codes the documentation lists as reserved or unassigned can't be typed
+pub fn is_virtual_key(code: u8) -> bool {
+    !matches!(
+        code,
+        0x00 | 0x07
+            | 0x0A..=0x0B
+            | 0x0E..=0x0F
+            | 0x3A..=0x40
+            | 0x5E
+            | 0x88..=0x8F
+            | 0x97..=0x9F
+            | 0xB8..=0xB9
+            | 0xC1..=0xC2
+            | 0xE0
+            | 0xE8
+            | 0xFF
+    )
+}
+
+pub fn parse_key_name(name: &str) -> Option<VIRTUAL_KEY> {
+    let name = name.to_ascii_lowercase();
+
+    // Letters and digits use their uppercase ASCII code as virtual key code
+    if let [c @ (b'a'..=b'z' | b'0'..=b'9')] = name.as_bytes() {
+        return Some(VIRTUAL_KEY(c.to_ascii_uppercase() as u16));
+    }
+
+    if let Some(n) = name.strip_prefix("numpad") {
+        return match n.parse::<u16>() {
+            Ok(n @ 0..=9) => Some(VIRTUAL_KEY(VK_NUMPAD0.0 + n)),
+            _ => None,
+        };
+    }
+    if let Some(n) = name.strip_prefix('f') {
+        if let Ok(n @ 1..=24) = n.parse::<u16>() {
+            return Some(VIRTUAL_KEY(VK_F1.0 + n - 1));
+        }
+    }
+
+    NAMED_KEYS
+        .iter()
+        .find(|(key_name, _)| *key_name == name)
+        .map(|&(_, key)| key)
+}
+
+// Modifier bits VkKeyScanExW returns in its high byte
+const MODIFIERS: &[(u8, VIRTUAL_KEY)] = &[(1, VK_SHIFT), (2, VK_CONTROL), (4, VK_MENU)];
+
+// Layout characters are resolved with, 0 for the current one of the thread
+// reading the mappings
+static LAYOUT: AtomicIsize = AtomicIsize::new(0);
+// Set to resolve them with a US layout built in instead of asking Windows
+static US: AtomicBool = AtomicBool::new(false);
+
+// US keys for the characters whose shifted version is on another key, along
+// with it
+const US_PUNCTUATION: &[(char, char, VIRTUAL_KEY)] = &[
+    ('`', '~', VK_OEM_3),
+    ('-', '_', VK_OEM_MINUS),
+    ('=', '+', VK_OEM_PLUS),
+    ('[', '{', VK_OEM_4),
+    (']', '}', VK_OEM_6),
+    ('\\', '|', VK_OEM_5),
+    (';', ':', VK_OEM_1),
+    ('\'', '"', VK_OEM_7),
+    (',', '<', VK_OEM_COMMA),
+    ('.', '>', VK_OEM_PERIOD),
+    ('/', '?', VK_OEM_2),
+];
+// Typed with shift and the digit at the same index
+const US_SHIFTED_DIGITS: &str = ")!@#$%^&*(";
+
+// Loads a layout by its id like 0000040C without switching to it
+pub fn set_layout(id: &str) -> windows::core::Result<()> {
+    let layout = unsafe { LoadKeyboardLayoutW(&HSTRING::from(id), KLF_NOTELLSHELL)? };
+    LAYOUT.store(layout.0, Ordering::Relaxed);
+    Ok(())
+}
+
+// The same on every machine, for checking configs without depending on its layout
+pub fn use_us_layout() {
+    US.store(true, Ordering::Relaxed);
+}
+
+fn layout() -> HKL {
+    match LAYOUT.load(Ordering::Relaxed) {
+        0 => unsafe { GetKeyboardLayout(0) },
+        layout => HKL(layout),
+    }
+}
+
+// The modifiers the layout needs held for a character followed by its key,
+// None for characters it can't type
+pub fn parse_char(c: char) -> Option<Vec<VIRTUAL_KEY>> {
+    let scan = match US.load(Ordering::Relaxed) {
+        true => us_scan(c),
+        false => unsafe { VkKeyScanExW(u16::try_from(u32::from(c)).ok()?, layout()) },
+    };
+    let [key, modifiers] = (scan as u16).to_le_bytes();
+    // -1 when there's no key for it, the other bits are for keys like Hankaku
+    // which can't be pressed the same way
+    if scan == -1 || modifiers & !0b111 != 0 {
+        return None;
+    }
+
+    let mut keys: Vec<VIRTUAL_KEY> = MODIFIERS
+        .iter()
+        .filter(|(bit, _)| modifiers & bit != 0)
+        .map(|&(_, key)| key)
+        .collect();
+    keys.push(VIRTUAL_KEY(key as u16));
+    Some(keys)
+}
+
+// Like VkKeyScanExW with a US layout, the key in the low byte and shift in the
+// high one
+fn us_scan(c: char) -> i16 {
+    const SHIFT: i16 = 0x100;
+    match c {
+        'a'..='z' => return c.to_ascii_uppercase() as i16,
+        'A'..='Z' => return SHIFT | c as i16,
+        '0'..='9' | ' ' => return c as i16,
+        _ => {}
+    }
+    if let Some(digit) = US_SHIFTED_DIGITS.find(c) {
+        return SHIFT | (b'0' + digit as u8) as i16;
+    }
+    for &(plain, shifted, key) in US_PUNCTUATION {
+        if c == plain {
+            return key.0 as i16;
+        }
+        if c == shifted {
+            return SHIFT | key.0 as i16;
+        }
+    }
+    -1
+}
+
+pub fn key_name(key: VIRTUAL_KEY) -> Option<String> {
+    match key.0 {
+        code @ (0x30..=0x39 | 0x41..=0x5A) => {
+            return Some(char::from(code as u8).to_ascii_lowercase().to_string())
+        }
+        code if (VK_NUMPAD0.0..=VK_NUMPAD0.0 + 9).contains(&code) => {
+            return Some(format!("numpad{}", code - VK_NUMPAD0.0))
+        }
+        code if (VK_F1.0..VK_F1.0 + 24).contains(&code) => {
+            return Some(format!("f{}", code - VK_F1.0 + 1))
+        }
+        _ => {}
+    }
+
+    NAMED_KEYS
+        .iter()
+        .find(|(_, named)| *named == key)
+        .map(|(name, _)| name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use windows::Win32::UI::Input::KeyboardAndMouse::{VK_1, VK_A};
+
+    use super::*;
+
+    #[test]
+    fn us_layout() {
+        assert_eq!(us_scan('a'), VK_A.0 as i16);
+        assert_eq!(us_scan('A'), 0x100 | VK_A.0 as i16);
+        assert_eq!(us_scan('!'), 0x100 | VK_1.0 as i16);
+        assert_eq!(us_scan('/'), VK_OEM_2.0 as i16);
+        assert_eq!(us_scan('?'), 0x100 | VK_OEM_2.0 as i16);
+        assert_eq!(us_scan('é'), -1);
+    }
+}
@@ -0,0 +1,50 @@
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+// How long each message took from being received by Windows, going by its
+// timestamp, to its inputs being sent
+#[derive(Clone, Default)]
+pub struct Latency(Arc<Mutex<Vec<Duration>>>);
+
+pub struct Summary {
+    count: usize,
+    min: Duration,
+    avg: Duration,
+    max: Duration,
+    p99: Duration,
+}
+
+impl Latency {
+    pub fn record(&self, latency: Duration) {
+        self.0.lock().unwrap().push(latency);
+    }
+
+    // None until something was sent
+    pub fn summary(&self) -> Option<Summary> {
+        let mut samples = self.0.lock().unwrap().clone();
+        samples.sort_unstable();
+
+        let count = samples.len();
+        let total: Duration = samples.iter().sum();
+        Some(Summary {
+            count,
+            min: *samples.first()?,
+            avg: total / count as u32,
+            max: *samples.last()?,
+            p99: samples[(count * 99).div_ceil(100) - 1],
+        })
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Latency over {} messages: min {:?}, avg {:?}, max {:?}, p99 {:?}",
+            self.count, self.min, self.avg, self.max, self.p99,
+        )
+    }
+}
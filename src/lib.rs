@@ -0,0 +1,194 @@
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use miette::Report;
+
+use crate::mappings::MappingsError;
+
+// Prints a status message unless running quietly
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if !$crate::is_quiet() {
+            println!($($arg)*);
+        }
+    };
+}
+
+pub mod config;
+pub mod device;
+pub mod focus;
+pub mod handler;
+pub mod input;
+pub mod keys;
+pub mod latency;
+pub mod listener;
+pub mod log;
+pub mod mappings;
+pub mod monitor;
+pub mod notes;
+pub mod repeat;
+pub mod schedule;
+pub mod watch;
+
+#[derive(Debug, thiserror::Error, miette::Diagnostic)]
+pub enum Error {
+    #[error(transparent)]
+    #[diagnostic(transparent)]
+    Config(MappingsError),
+
+    #[error("Unsupported mappings file {}", .0.display())]
+    #[diagnostic(code(config), help("use a .toml, .json, .yaml or .yml file"))]
+    UnknownFormat(PathBuf),
+
+    #[error("{} already exists", .0.display())]
+    #[diagnostic(code(init), help("pass --force to overwrite it"))]
+    ConfigExists(PathBuf),
+
+    #[error("No MIDI devices found")]
+    #[diagnostic(
+        code(devices),
+        help("connect a device and make sure no other program has it open, or pass --wait-for-device to wait for one")
+    )]
+    NoMidiDevices,
+
+    #[error("No MIDI device selected")]
+    #[diagnostic(
+        code(devices),
+        help("pass --device since --quiet, --tray and builds without the interactive feature don't ask which one to use")
+    )]
+    DeviceRequired,
+
+    #[error("Whether to print received messages wasn't chosen")]
+    #[diagnostic(
+        code(debug),
+        help("pass --debug or --no-debug since builds without the interactive feature don't ask")
+    )]
+    DebugRequired,
+
+    #[error("Setup was interrupted")]
+    #[diagnostic(
+        code(prompt),
+        help("pass --device and --debug or --no-debug to start without being asked anything")
+    )]
+    Prompt(#[source] std::io::Error),
+
+    #[error("No MIDI device matches {0}")]
+    #[diagnostic(
+        code(devices),
+        help("run with --list-devices to see available devices")
+    )]
+    DeviceNotFound(String),
+
+    #[error("Couldn't open {name}")]
+    #[diagnostic(
+        code(devices),
+        help("close other programs using the device, like a DAW, and try again")
+    )]
+    DeviceBusy {
+        name: String,
+        #[source]
+        source: windows::core::Error,
+    },
+
+    #[error("Multiple MIDI devices match {query}")]
+    #[diagnostic(code(devices))]
+    AmbiguousDevice {
+        query: String,
+        #[help]
+        candidates: String,
+    },
+
+    #[error("Couldn't load keyboard layout {id}")]
+    #[diagnostic(
+        code(layout),
+        help("layouts are listed under HKEY_LOCAL_MACHINE\\SYSTEM\\CurrentControlSet\\Control\\Keyboard Layouts")
+    )]
+    UnknownLayout {
+        id: String,
+        #[source]
+        source: windows::core::Error,
+    },
+
+    #[error("Couldn't send a test input")]
+    #[diagnostic(
+        code(input),
+        help("something is blocking input like anti-cheat software or a window running as administrator, try running m2k as administrator")
+    )]
+    SelfTest(#[source] windows::core::Error),
+
+    #[error("Input was blocked by a window running as administrator")]
+    #[diagnostic(
+        code(input),
+        help("run m2k as administrator to send input to elevated windows")
+    )]
+    InputBlocked,
+
+    #[error("Notes mapped with note_out won't be sent anywhere")]
+    #[diagnostic(
+        code(forward),
+        help("pass --forward with the MIDI output to send them to")
+    )]
+    NoteOutWithoutForward,
+
+    #[error("Windows error")]
+    #[diagnostic(code(os))]
+    Windows(#[from] windows::core::Error),
+
+    #[error("IO error")]
+    #[diagnostic(code(io))]
+    Io(#[from] std::io::Error),
+
+    #[error("File watcher error")]
+    #[diagnostic(code(watch))]
+    Watch(#[from] notify::Error),
+
+    #[error("Serialization error")]
+    #[diagnostic(code(serialize))]
+    Serialize(#[from] toml::ser::Error),
+
+    #[error("Cancellation signal error")]
+    #[diagnostic(code(signal))]
+    Cancellation(#[from] ctrlc::Error),
+}
+
+// Status messages are only printed when unset, errors always are
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+// Errors only get their message unless set
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+pub fn is_verbose() -> bool {
+    VERBOSE.load(Ordering::Relaxed)
+}
+
+#[cold]
+pub fn report_error(error: impl Into<Error>) {
+    let report = Report::from(error.into());
+    if !is_verbose() {
+        eprintln!("Error: {report}");
+        return;
+    }
+
+    // The full report with the causes and help, then the codes Windows errors
+    // come with which are what to search for
+    eprintln!("Error: {report:?}");
+    for cause in report.chain() {
+        if let Some(error) = cause.downcast_ref::<windows::core::Error>() {
+            eprintln!("HRESULT {:#010X}", error.code().0);
+        }
+    }
+}
@@ -0,0 +1,298 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use windows::Devices::Midi::{MidiInPort, MidiOutPort};
+
+use crate::{
+    device::{self, Connection},
+    focus::Focus,
+    handler::{Decision, Event, Handler, Hook},
+    input,
+    latency::Latency,
+    log::Log,
+    mappings::SharedMappings,
+    monitor::Monitor,
+    repeat::Repeats,
+    report_error,
+    schedule::{self, Tag},
+    Error,
+};
+
+const STALE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// Options for handling the messages of one or more devices sharing the same mappings
+pub struct Builder {
+    devices: Vec<device::Opened>,
+    mappings: SharedMappings,
+    debug: bool,
+    json: bool,
+    log: Option<Log>,
+    monitor: Option<Monitor>,
+    latency: Option<Latency>,
+    release_after: Option<Duration>,
+    forward: Option<MidiOutPort>,
+    release_on_focus_change: bool,
+    setup: bool,
+    beep: bool,
+    exit: Option<Arc<dyn Fn() + Send + Sync>>,
+    hook: Option<Hook>,
+}
+
+impl Builder {
+    pub fn new(mappings: SharedMappings) -> Self {
+        Self {
+            devices: vec![],
+            mappings,
+            debug: false,
+            json: false,
+            log: None,
+            monitor: None,
+            latency: None,
+            release_after: None,
+            forward: None,
+            release_on_focus_change: false,
+            setup: false,
+            beep: false,
+            exit: None,
+            hook: None,
+        }
+    }
+
+    // Message timestamps count from when the device was opened, so latency is
+    // only measured right if it's passed right after opening it
+    pub fn device(self, device: MidiInPort) -> Self {
+        self.device_opened_at(device, Instant::now())
+    }
+
+    pub fn devices(mut self, devices: impl IntoIterator<Item = MidiInPort>) -> Self {
+        let opened = Instant::now();
+        self.devices
+            .extend(devices.into_iter().map(|device| (device, opened)));
+        self
+    }
+
+    pub fn device_opened_at(mut self, device: MidiInPort, opened: Instant) -> Self {
+        self.devices.push((device, opened));
+        self
+    }
+
+    // Prints incoming note numbers
+    pub fn debug(mut self, debug: bool) -> Self {
+        self.debug = debug;
+        self
+    }
+
+    // Prints every message as a line of JSON instead, for other programs
+    pub fn json(mut self, json: bool) -> Self {
+        self.json = json;
+        self
+    }
+
+    pub fn log(mut self, log: impl Into<Option<Log>>) -> Self {
+        self.log = log.into();
+        self
+    }
+
+    pub fn monitor(mut self, monitor: impl Into<Option<Monitor>>) -> Self {
+        self.monitor = monitor.into();
+        self
+    }
+
+    // Records how long each message takes to handle
+    pub fn latency(mut self, latency: impl Into<Option<Latency>>) -> Self {
+        self.latency = latency.into();
+        self
+    }
+
+    // Releases keys held for longer than this in case their note off was missed
+    pub fn release_after(mut self, timeout: impl Into<Option<Duration>>) -> Self {
+        self.release_after = timeout.into();
+        self
+    }
+
+    // Sends every received message on to the output as is after handling it,
+    // and where note_out mappings send their notes
+    pub fn forward(mut self, output: impl Into<Option<MidiOutPort>>) -> Self {
+        self.forward = output.into();
+        self
+    }
+
+    // Releases everything like an All Notes Off message whenever another window
+    // comes to the foreground, stopping repeats and macros as well
+    pub fn release_on_focus_change(mut self, release: bool) -> Self {
+        self.release_on_focus_change = release;
+        self
+    }
+
+    // Prints a line for every note on nothing is mapped to, for finding out
+    // which pads still need a mapping
+    pub fn setup(mut self, setup: bool) -> Self {
+        self.setup = setup;
+        self
+    }
+
+    // Rings the terminal bell along with the setup line
+    pub fn beep(mut self, beep: bool) -> Self {
+        self.beep = beep;
+        self
+    }
+
+    // Called from the device's thread when the exit chord of the mappings is
+    // played, which is ignored otherwise
+    pub fn on_exit(mut self, exit: impl Fn() + Send + Sync + 'static) -> Self {
+        self.exit = Some(Arc::new(exit));
+        self
+    }
+
+    // Called from the device's thread with every message that isn't ignored,
+    // deciding whether its mapping is sent. Devices share the same closure.
+    pub fn on_event(mut self, hook: impl Fn(&Event) -> Decision + Send + Sync + 'static) -> Self {
+        self.hook = Some(Arc::new(hook));
+        self
+    }
+
+    pub fn listen(self) -> Result<Listener, Error> {
+        if self.devices.is_empty() {
+            return Err(Error::NoMidiDevices);
+        }
+        // Still listens since the rest of the mappings work without an output
+        if self.forward.is_none() && self.mappings.read().unwrap().sends_notes() {
+            report_error(Error::NoteOutWithoutForward);
+        }
+
+        // Each device gets its own state so notes held on one don't affect the others
+        let tag = Tag::new();
+        let mut devices = vec![];
+        for (device, opened) in self.devices {
+            let repeats = Arc::new(Repeats::new(tag));
+            let macros = Arc::new(Repeats::new(tag));
+            let handler = Handler::new(
+                repeats.clone(),
+                macros.clone(),
+                self.debug,
+                self.json,
+                self.log.clone(),
+                self.monitor.clone(),
+                self.latency.clone(),
+            )
+            .forward(self.forward.clone())
+            .setup(self.setup, self.beep)
+            .exit(self.exit.clone())
+            .hook(self.hook.clone());
+            let handler = Arc::new(Mutex::new(handler));
+            let mappings = self.mappings.clone();
+            let connection = device::connect(
+                device,
+                opened,
+                Arc::new({
+                    let handler = handler.clone();
+                    move |message, received| {
+                        let mappings = mappings.read().unwrap().clone();
+                        let mut handler = handler.lock().unwrap();
+                        if let Err(error) = handler.handle(&message, received, &mappings) {
+                            report_error(error);
+                        }
+                    }
+                }),
+            )?;
+            devices.push(Device {
+                connection,
+                handler,
+                repeats: [repeats, macros],
+            });
+        }
+
+        let stale = self.release_after.map(|timeout| {
+            let stop = Arc::new(AtomicBool::new(false));
+            let thread = thread::spawn({
+                let stop = stop.clone();
+                move || release_stale(timeout, &stop)
+            });
+            (stop, thread)
+        });
+
+        let focus = if self.release_on_focus_change {
+            let handlers: Vec<Arc<Mutex<Handler>>> = devices
+                .iter()
+                .map(|device| device.handler.clone())
+                .collect();
+            Some(Focus::spawn(move || {
+                for handler in &handlers {
+                    let inputs = handler.lock().unwrap().reset();
+                    input::send(inputs);
+                }
+            })?)
+        } else {
+            None
+        };
+
+        Ok(Listener {
+            devices,
+            focus,
+            log: self.log,
+            monitor: self.monitor,
+            stale,
+            tag,
+        })
+    }
+}
+
+// Handles messages until stopped
+pub struct Listener {
+    devices: Vec<Device>,
+    focus: Option<Focus>,
+    log: Option<Log>,
+    monitor: Option<Monitor>,
+    stale: Option<(Arc<AtomicBool>, JoinHandle<()>)>,
+    // Shared by every device's repeats, macros and delayed releases
+    tag: Tag,
+}
+
+struct Device {
+    connection: Connection,
+    handler: Arc<Mutex<Handler>>,
+    // Repeats and macros
+    repeats: [Arc<Repeats>; 2],
+}
+
+impl Listener {
+    // Keys still held are released once no message can press them again
+    pub fn stop(mut self) -> Result<(), Error> {
+        self.focus = None;
+        if let Some((stop, thread)) = self.stale {
+            stop.store(true, Ordering::Release);
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+        for device in &self.devices {
+            device.connection.close()?;
+            // Waits for a message that was already being handled
+            drop(device.handler.lock().unwrap());
+            for repeats in &device.repeats {
+                repeats.stop_all();
+            }
+        }
+        schedule::clear(self.tag);
+        input::release_all();
+        if let Some(monitor) = &self.monitor {
+            monitor.stop()?;
+        }
+        if let Some(log) = &self.log {
+            log.flush()?;
+        }
+        Ok(())
+    }
+}
+
+fn release_stale(timeout: Duration, stop: &AtomicBool) {
+    while !stop.load(Ordering::Acquire) {
+        thread::park_timeout(timeout.min(STALE_CHECK_INTERVAL));
+        input::release_stale(timeout);
+    }
+}
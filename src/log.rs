@@ -0,0 +1,192 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex, Weak},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::json;
+
+use crate::{handler::Event, report_error};
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// Writes go to a buffer so logging doesn't add a syscall to every message, and
+// a background thread flushes it for as long as the log is alive
+#[derive(Clone)]
+pub struct Log(Arc<Mutex<BufWriter<File>>>);
+
+impl Log {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let writer = Arc::new(Mutex::new(BufWriter::new(File::create(path)?)));
+
+        let weak = Arc::downgrade(&writer);
+        thread::spawn(move || flush_periodically(weak));
+
+        Ok(Self(writer))
+    }
+
+    // One tab separated line per message, with the action it resolved to
+    pub fn event(&self, event: &Event, mapped: Option<String>) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let Fields {
+            channel,
+            ty,
+            number,
+            value,
+        } = fields(event);
+
+        let mut writer = self.0.lock().unwrap();
+        let result = writeln!(
+            writer,
+            "{}.{:03}\t{}\t{ty}\t{}\t{}\t{}",
+            timestamp.as_secs(),
+            timestamp.subsec_millis(),
+            or_dash(channel.map(|channel| channel + 1)),
+            or_dash(number),
+            or_dash(value),
+            mapped.as_deref().unwrap_or("unmapped"),
+        );
+        if let Err(error) = result {
+            report_error(error);
+        }
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+// The columns of a message, shared by the log and the JSON output
+struct Fields<'a> {
+    channel: Option<u8>,
+    ty: &'static str,
+    number: Option<u16>,
+    value: Option<Value<'a>>,
+}
+
+enum Value<'a> {
+    Number(u8),
+    Bytes(&'a [u8]),
+}
+
+fn fields(event: &Event) -> Fields<'_> {
+    let (channel, ty, number, value) = match *event {
+        Event::NoteOn {
+            channel,
+            note,
+            velocity,
+        } => (
+            Some(channel),
+            "note_on",
+            Some(note.into()),
+            Some(Value::Number(velocity)),
+        ),
+        Event::NoteOff { channel, note } => (Some(channel), "note_off", Some(note.into()), None),
+        Event::ControlChange {
+            channel,
+            controller,
+            value,
+        } => (
+            Some(channel),
+            "control_change",
+            Some(controller.into()),
+            Some(Value::Number(value)),
+        ),
+        Event::PitchBend { channel, value } => (Some(channel), "pitch_bend", Some(value), None),
+        Event::ProgramChange { channel, program } => {
+            (Some(channel), "program_change", Some(program.into()), None)
+        }
+        Event::ChannelPressure { channel, pressure } => (
+            Some(channel),
+            "channel_pressure",
+            None,
+            Some(Value::Number(pressure)),
+        ),
+        Event::PolyPressure {
+            channel,
+            note,
+            pressure,
+        } => (
+            Some(channel),
+            "poly_pressure",
+            Some(note.into()),
+            Some(Value::Number(pressure)),
+        ),
+        // SysEx messages don't have a channel, their bytes are the value
+        Event::SysEx { ref data } => (None, "sysex", None, Some(Value::Bytes(data))),
+        Event::Transport(transport) => (None, transport.name(), None, None),
+    };
+    Fields {
+        channel,
+        ty,
+        number,
+        value,
+    }
+}
+
+// Bytes are written in hex
+impl fmt::Display for Value<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Number(value) => write!(f, "{value}"),
+            Value::Bytes(bytes) => {
+                for (i, byte) in bytes.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(" ")?;
+                    }
+                    write!(f, "{byte:02X}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// One line of JSON per message for other programs to read, with whether it
+// sent anything
+pub fn json(event: &Event, mapped: Option<&str>, sent: bool) -> String {
+    let Fields {
+        channel,
+        ty,
+        number,
+        value,
+    } = fields(event);
+    let value = match value {
+        Some(Value::Number(value)) => json!(value),
+        Some(bytes) => json!(bytes.to_string()),
+        None => json!(null),
+    };
+    json!({
+        "type": ty,
+        "channel": channel.map(|channel| channel + 1),
+        "note": number,
+        "velocity": value,
+        "key": mapped,
+        "sent": sent,
+    })
+    .to_string()
+}
+
+fn or_dash(value: Option<impl ToString>) -> String {
+    value.map_or_else(|| "-".to_owned(), |value| value.to_string())
+}
+
+fn flush_periodically(writer: Weak<Mutex<BufWriter<File>>>) {
+    loop {
+        thread::sleep(FLUSH_INTERVAL);
+        let writer = match writer.upgrade() {
+            Some(writer) => writer,
+            None => return,
+        };
+        let result = writer.lock().unwrap().flush();
+        if let Err(error) = result {
+            report_error(error);
+        }
+    }
+}
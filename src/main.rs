@@ -1,211 +1,454 @@
 use std::{
     env,
-    mem::size_of,
+    fs::OpenOptions,
+    io::{self, Write},
+    iter,
+    path::{Path, PathBuf},
     process,
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        Arc, RwLock,
     },
-    thread,
+    thread::{self, Thread},
+    time::Duration,
 };
 
-use dialoguer::{theme::ColorfulTheme, Confirm, FuzzySelect};
-use miette::{Report, Result};
-use windows::{
-    core::ComInterface,
-    Devices::{
-        Enumeration::DeviceInformation,
-        Midi::{
-            IMidiMessage, MidiInPort, MidiMessageReceivedEventArgs, MidiMessageType,
-            MidiNoteOffMessage, MidiNoteOnMessage,
-        },
-    },
-    Foundation::TypedEventHandler,
-    Win32::UI::{
-        Input::KeyboardAndMouse::{
-            SendInput, INPUT, INPUT_0, INPUT_KEYBOARD, KEYBDINPUT, KEYBD_EVENT_FLAGS,
-            KEYEVENTF_KEYUP,
-        },
-        WindowsAndMessaging::GetMessageExtraInfo,
-    },
-};
-
-use crate::mappings::{Mappings, MappingsError};
-
-mod mappings;
-
-fn main() -> Result<()> {
-    let run = with_shutdown();
-    let mappings = read_mappings()?;
-    let (device, debug) = read_options()?;
+use clap::Parser;
+use miette::{MietteHandlerOpts, Result};
+use windows::core::HSTRING;
 
-    run(mappings, device, debug).map_err(Into::into)
-}
+use m2k::{
+    device, input, is_quiet, keys,
+    latency::Latency,
+    listener::Builder,
+    log::Log,
+    mappings::Mappings,
+    monitor::{self, Monitor},
+    report_error,
+    watch::watch_mappings,
+    Error,
+};
 
-fn handle_midi_message(
-    message: &IMidiMessage,
-    mappings: &Mappings,
-    debug: bool,
-) -> Result<(), windows::core::Error> {
-    let ty = message.Type()?;
+use crate::{args::Args, settings::Settings};
 
-    let (note, ty) = match ty {
-        MidiMessageType::NoteOn => {
-            let message: MidiNoteOnMessage = message.cast()?;
-            let note = message.Note()?;
+mod args;
+mod prompt;
+mod settings;
+mod tray;
 
-            if debug {
-                println!("{note}");
-            }
+const CONFIG_FILE: &str = "m2k.toml";
+// TOML mappings used when there's no file, for setups without one
+const CONFIG_VAR: &str = "M2K_CONFIG";
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
-            (note, KEYBD_EVENT_FLAGS(0))
+fn main() -> Result<()> {
+    let mut args = Args::parse();
+    if !args.color() {
+        let _ = miette::set_hook(Box::new(|_| {
+            Box::new(MietteHandlerOpts::new().color(false).build())
+        }));
+    }
+    m2k::set_verbose(args.verbose);
+    // Before anything reads the mappings, which looks characters up in it
+    if let Some(id) = &args.layout {
+        keys::set_layout(id).map_err(|source| Error::UnknownLayout {
+            id: id.clone(),
+            source,
+        })?;
+    }
+    if args.list_devices {
+        return list_devices().map_err(Into::into);
+    }
+    if args.init {
+        return init(args.force).map_err(Into::into);
+    }
+    // Only parses and validates, so it also works where there's no MIDI or input.
+    // Characters go by a US layout so the result doesn't depend on the machine.
+    if let Some(path) = &args.config_check {
+        if args.layout.is_none() {
+            keys::use_us_layout();
         }
-        MidiMessageType::NoteOff => {
-            let message: MidiNoteOffMessage = message.cast()?;
-            let note = message.Note()?;
-            (note, KEYEVENTF_KEYUP)
+        Mappings::from_file(path)?;
+        println!("{} is valid", path.display());
+        return Ok(());
+    }
+    if args.print_config {
+        let (mappings, _) = read_mappings(args.config, &[])?;
+        print!("{}", mappings.table());
+        return Ok(());
+    }
+
+    // There's no console to ask anything in or print to
+    args.quiet |= args.tray;
+    // Status messages would get in the way of the JSON
+    m2k::set_quiet(args.quiet || args.json);
+    input::set_dry_run(args.dry_run);
+    if args.self_test {
+        input::self_test()?;
+        if !is_quiet() {
+            println!("Sending input works");
         }
-        _ => return Ok(()),
+    }
+    let (exit, run) = with_shutdown(args.shutdown_timeout);
+    // The devices come first since they can have their own mappings
+    let (devices, names, debug) = read_options(&args, &exit.should_exit)?;
+    let (mappings, paths) = read_mappings(args.config.clone(), &names)?;
+    let _tray = if args.tray {
+        Some(tray::Tray::spawn(names.join(", "), exit.clone())?)
+    } else {
+        None
+    };
+
+    let mappings = Arc::new(RwLock::new(Arc::new(mappings)));
+    let _watcher = if paths.is_empty() {
+        None
+    } else {
+        Some(watch_mappings(&paths, mappings.clone())?)
     };
 
-    let key = match mappings.get(note) {
-        Some(key) => key,
-        None => return Ok(()),
+    let forward = match &args.forward {
+        Some(query) => Some(device::open_output(query)?),
+        None => None,
     };
 
-    let input = INPUT {
-        r#type: INPUT_KEYBOARD,
-        Anonymous: INPUT_0 {
-            ki: KEYBDINPUT {
-                wVk: key,
-                wScan: 0,
-                dwFlags: ty,
-                time: 0,
-                dwExtraInfo: unsafe { GetMessageExtraInfo().0 as usize },
-            },
-        },
+    let log = match &args.log {
+        Some(path) => Some(Log::create(path).map_err(Error::from)?),
+        None => None,
     };
-    let sent = unsafe { SendInput(&[input], size_of::<INPUT>() as i32) };
 
-    if sent == 1 {
-        Ok(())
+    let monitor = if args.monitor {
+        Some(Monitor::start().map_err(Error::from)?)
     } else {
-        Err(windows::core::Error::from_win32())
+        None
+    };
+
+    let latency = args.measure_latency.then(Latency::default);
+
+    let builder = devices
+        .into_iter()
+        .fold(Builder::new(mappings), |builder, (device, opened)| {
+            builder.device_opened_at(device, opened)
+        })
+        .debug(debug)
+        .json(args.json)
+        .log(log)
+        .monitor(monitor)
+        .latency(latency.clone())
+        .release_after(args.release_after)
+        .forward(forward)
+        .release_on_focus_change(args.release_on_focus_change)
+        .setup(args.setup)
+        .beep(args.beep)
+        .on_exit({
+            let exit = exit.clone();
+            move || exit.request()
+        });
+    run(builder)?;
+
+    if let Some(summary) = latency.and_then(|latency| latency.summary()) {
+        println!("{summary}");
     }
+    Ok(())
 }
 
-fn with_shutdown() -> impl Fn(Mappings, MidiInPort, bool) -> Result<(), Error> {
-    let should_exit = Arc::new(AtomicBool::new(false));
+// The first Ctrl+C stops listening and releases everything, exiting forcefully
+// if that takes longer than the timeout or if Ctrl+C is pressed again. Held keys
+// are still released when forced.
+fn with_shutdown(timeout: Duration) -> (Exit, impl Fn(Builder) -> Result<(), Error>) {
+    let exit = Exit {
+        should_exit: Arc::new(AtomicBool::new(false)),
+        main_thread: thread::current(),
+        timeout,
+    };
 
     ctrlc::set_handler({
-        let should_exit = should_exit.clone();
-        let main_thread = thread::current();
-        move || {
-            if should_exit.swap(true, Ordering::AcqRel) {
-                process::exit(1);
-            }
-            main_thread.unpark();
-        }
+        let exit = exit.clone();
+        move || exit.request()
     })
     .unwrap();
 
-    move |mappings, device, debug| {
-        device.MessageReceived(
-            &TypedEventHandler::<MidiInPort, MidiMessageReceivedEventArgs>::new(move |_, event| {
-                let message = match event.as_ref() {
-                    Some(event) => event.Message()?,
-                    None => return Ok(()),
-                };
-
-                if let Err(error) = handle_midi_message(&message, &mappings, debug) {
-                    report_error(error);
-                }
-                Ok(())
-            }),
-        )?;
-
-        while !should_exit.load(Ordering::Acquire) {
-            thread::park();
+    let run = {
+        let should_exit = exit.should_exit.clone();
+        move |builder: Builder| {
+            let listener = builder.listen()?;
+            while !should_exit.load(Ordering::Acquire) {
+                thread::park();
+            }
+            listener.stop()?;
+
+            let (failed, sent) = input::failures();
+            if failed > 0 {
+                eprintln!("{failed} of {sent} inputs failed");
+            }
+            Ok(())
         }
-        Ok(())
+    };
+    (exit, run)
+}
+
+// Asks the main thread to shut down, for Ctrl+C and quitting from the tray. The
+// flag is also set before listening starts, for whatever is waiting on the main
+// thread until then.
+#[derive(Clone)]
+pub struct Exit {
+    should_exit: Arc<AtomicBool>,
+    main_thread: Thread,
+    timeout: Duration,
+}
+
+impl Exit {
+    pub fn request(&self) {
+        if self.should_exit.swap(true, Ordering::AcqRel) {
+            force_exit();
+        }
+        self.main_thread.unpark();
+        let timeout = self.timeout;
+        thread::spawn(move || {
+            thread::sleep(timeout);
+            eprintln!("Shutting down took too long, exiting");
+            force_exit();
+        });
     }
 }
 
-#[derive(Debug, thiserror::Error, miette::Diagnostic)]
-pub enum Error {
-    #[error(transparent)]
-    #[diagnostic(transparent)]
-    Config(MappingsError),
+fn force_exit() -> ! {
+    input::release_all();
+    if let Err(error) = monitor::restore() {
+        report_error(error);
+    }
+    process::exit(1);
+}
 
-    #[error("No MIDI devices found")]
-    #[diagnostic(code(devices))]
-    NoMidiDevices,
+// Explicit paths come first, then a file named after one of the devices in the
+// config directory, then m2k.toml in the current directory and then in the
+// config directory
+fn read_mappings(
+    mut paths: Vec<PathBuf>,
+    devices: &[String],
+) -> Result<(Mappings, Vec<PathBuf>), Error> {
+    if paths.is_empty() {
+        let directory = settings::config_dir();
+        let named = directory.iter().flat_map(|directory| {
+            devices
+                .iter()
+                .flat_map(|device| device_configs(directory, device))
+        });
+        let local = PathBuf::from(CONFIG_FILE);
+        let global = directory
+            .as_ref()
+            .map(|directory| directory.join(CONFIG_FILE));
+        paths.extend(
+            named
+                .chain(iter::once(local))
+                .chain(global)
+                .find(|path| path.is_file()),
+        );
+    }
 
-    #[error("Windows error")]
-    #[diagnostic(code(os))]
-    Windows(#[from] windows::core::Error),
+    if !paths.is_empty() {
+        if !is_quiet() {
+            let names: Vec<String> = paths
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect();
+            println!("Using mappings from {}", names.join(", "));
+        }
+        Ok((Mappings::from_files(&paths)?, paths))
+    } else if let Ok(source) = env::var(CONFIG_VAR) {
+        if !is_quiet() {
+            println!("Using mappings from {CONFIG_VAR}");
+        }
+        Ok((source.parse()?, vec![]))
+    } else {
+        if !is_quiet() {
+            println!("Using default mappings");
+        }
+        Ok((Mappings::hardcoded(), vec![]))
+    }
+}
+
+// Named like the device with the characters Windows doesn't allow in file names
+// replaced, in any of the formats mappings can be written in
+fn device_configs(directory: &Path, device: &str) -> impl Iterator<Item = PathBuf> {
+    let name: String = device
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let directory = directory.to_path_buf();
+    ["toml", "json", "yaml", "yml"]
+        .into_iter()
+        .map(move |extension| directory.join(format!("{name}.{extension}")))
+}
 
-    #[error("IO error")]
-    #[diagnostic(code(io))]
-    Io(#[from] std::io::Error),
+fn list_devices() -> Result<(), Error> {
+    let (midi_names, midi_ids) = device::enumerate()?;
+    for (name, id) in midi_names.iter().zip(&midi_ids) {
+        println!("{name}\t{id}");
+    }
 
-    #[error("Cancellation signal error")]
-    #[diagnostic(code(signal))]
-    Cancellation(#[from] ctrlc::Error),
+    let (output_names, output_ids) = device::enumerate_outputs()?;
+    if !output_names.is_empty() {
+        println!("\nOutputs for --forward");
+        for (name, id) in output_names.iter().zip(&output_ids) {
+            println!("{name}\t{id}");
+        }
+    }
+    Ok(())
 }
 
-fn read_mappings() -> Result<Mappings, Error> {
-    let path = env::args().nth(1);
-    if let Some(path) = path {
-        Mappings::from_file(path)
+fn init(force: bool) -> Result<(), Error> {
+    let example = Mappings::example()?;
+
+    let mut options = OpenOptions::new();
+    options.write(true);
+    if force {
+        options.create(true).truncate(true);
     } else {
-        Ok(Mappings::hardcoded())
+        options.create_new(true);
     }
+
+    let mut file = match options.open(CONFIG_FILE) {
+        Ok(file) => file,
+        Err(error) if error.kind() == io::ErrorKind::AlreadyExists => {
+            return Err(Error::ConfigExists(CONFIG_FILE.into()))
+        }
+        Err(error) => return Err(error.into()),
+    };
+    file.write_all(example.as_bytes())?;
+
+    println!("Wrote {CONFIG_FILE}");
+    Ok(())
 }
 
-fn read_options() -> Result<(MidiInPort, bool), Error> {
-    let midi_device_selector = MidiInPort::GetDeviceSelector()?;
-    let midi_devices = DeviceInformation::FindAllAsyncAqsFilter(&midi_device_selector)?.get()?;
+// Polls until the devices passed with --device, or any device without it, are
+// connected. Nothing has been set up yet so Ctrl+C just exits.
+fn wait_for_devices(
+    queries: &[String],
+    should_exit: &AtomicBool,
+) -> Result<(Vec<HSTRING>, Vec<HSTRING>), Error> {
+    let mut waiting = false;
+    loop {
+        let (midi_names, midi_ids) = device::enumerate()?;
+        let connected = !midi_ids.is_empty()
+            && queries.iter().all(|query| {
+                query == "all"
+                    || !matches!(
+                        device::find(query, &midi_names, &midi_ids),
+                        Err(Error::DeviceNotFound(_))
+                    )
+            });
+        if connected {
+            return Ok((midi_names, midi_ids));
+        }
 
-    let (midi_names, midi_ids) = midi_devices
-        .into_iter()
-        .filter_map(|device| {
-            let name = device.Name().ok()?;
-            let id = device.Id().ok()?;
-            Some((name, id))
-        })
-        .fold((vec![], vec![]), |(mut names, mut ids), (name, id)| {
-            names.push(name);
-            ids.push(id);
-            (names, ids)
-        });
+        if !waiting && !is_quiet() {
+            println!("Waiting for a MIDI device to be connected");
+        }
+        waiting = true;
+        thread::park_timeout(DEVICE_POLL_INTERVAL);
+        if should_exit.load(Ordering::Acquire) {
+            process::exit(0);
+        }
+    }
+}
 
-    let theme = ColorfulTheme::default();
+// The devices come with their names
+fn read_options(
+    args: &Args,
+    should_exit: &AtomicBool,
+) -> Result<(Vec<device::Opened>, Vec<String>, bool), Error> {
+    let (midi_names, midi_ids) = if args.wait_for_device {
+        wait_for_devices(&args.devices, should_exit)?
+    } else {
+        device::enumerate()?
+    };
+
+    let mut settings = if args.reconfigure {
+        Settings::default()
+    } else {
+        Settings::load()
+    };
+    // The saved device is only used if it's still connected
+    let saved = settings
+        .device
+        .as_ref()
+        .and_then(|saved| midi_ids.iter().position(|id| *id == saved.as_str()));
 
-    let selected = match midi_ids.len() {
-        0 => return Err(Error::NoMidiDevices),
-        1 => 0,
-        _ => FuzzySelect::with_theme(&theme)
-            .with_prompt("MIDI device")
-            .items(&midi_names)
-            .interact()
-            .unwrap(),
+    let mut selected = match (midi_ids.len(), &args.devices[..], saved) {
+        (0, _, _) => return Err(Error::NoMidiDevices),
+        (_, [], Some(saved)) => vec![saved],
+        (1, [], None) => vec![0],
+        (_, [], None) if args.quiet => return Err(Error::DeviceRequired),
+        (_, [], None) => {
+            vec![prompt::device(&midi_names, args.color())?.ok_or(Error::DeviceRequired)?]
+        }
+        (_, devices, _) if devices.iter().any(|device| device == "all") => {
+            (0..midi_ids.len()).collect()
+        }
+        (_, devices, _) => devices
+            .iter()
+            .map(|device| device::find(device, &midi_names, &midi_ids))
+            .collect::<Result<_, _>>()?,
     };
-    let device_id = &midi_ids[selected];
+    // Listening to the same device twice would send everything twice
+    selected.sort_unstable();
+    selected.dedup();
 
-    let debug = Confirm::with_theme(&theme)
-        .with_prompt("Debug note IDs")
-        .default(false)
-        .interact()
-        .unwrap();
+    // A single device that fails to open can be swapped for another one when
+    // asking is allowed, several devices were passed explicitly
+    let devices = loop {
+        let devices = selected
+            .iter()
+            .map(|&selected| {
+                device::open(
+                    &midi_names[selected],
+                    &midi_ids[selected],
+                    args.open_retries,
+                )
+            })
+            .collect::<Result<Vec<_>, _>>();
+        match devices {
+            Ok(devices) => break devices,
+            Err(error) if prompt::INTERACTIVE && !args.quiet && selected.len() == 1 => {
+                report_error(error);
+                selected = prompt::device(&midi_names, args.color())?
+                    .into_iter()
+                    .collect();
+            }
+            Err(error) => return Err(error),
+        }
+    };
 
-    let device = MidiInPort::FromIdAsync(device_id)?.get()?;
+    let remembered = matches!(selected[..], [selected] if saved == Some(selected));
+    // Quiet runs don't print messages but keep the saved choice for later
+    let debug = if args.quiet || args.json {
+        false
+    } else if let Some(debug) = args.debug() {
+        debug
+    } else if remembered {
+        settings.debug
+    } else {
+        prompt::debug(settings.debug, args.color())?.ok_or(Error::DebugRequired)?
+    };
 
-    Ok((device, debug))
-}
+    // Only a single device is remembered since multiple ones are always passed explicitly
+    if let [selected] = selected[..] {
+        settings.device = Some(midi_ids[selected].to_string());
+    }
+    if !args.quiet && !args.json {
+        settings.debug = debug;
+    }
+    if let Err(error) = settings.save() {
+        report_error(error);
+    }
 
-#[cold]
-fn report_error(error: impl Into<Error>) {
-    let report = Report::from(error.into());
-    eprintln!("Error: {report}");
+    let names = selected
+        .iter()
+        .map(|&selected| midi_names[selected].to_string())
+        .collect();
+    Ok((devices, names, debug))
 }
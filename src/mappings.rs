@@ -1,85 +1,1161 @@
-use std::{fs, iter, path::Path};
+use std::{
+    collections::HashMap,
+    fmt, fs, iter,
+    ops::RangeInclusive,
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
 
 use miette::{Diagnostic, LabeledSpan};
-use serde::Deserialize;
+use serde::de::DeserializeOwned;
 use windows::Win32::UI::Input::KeyboardAndMouse::VIRTUAL_KEY;
 
-use crate::Error;
+use crate::{
+    config::{
+        self, Context, Curve, Encoding, FileAftertouch, FileBend, FileChord, FileControlChange,
+        FileFineControlChange, FileHold, FileMapping, FileMappings, FilePitchBend,
+        FileRelativeControlChange, FileRepeat, FileSustain, FileSysEx, FileTransport, Located,
+        MessageType, Mouse, MouseButton, Overlap, Prelude,
+    },
+    keys, notes, Error,
+};
 
-pub struct Mappings(Vec<Option<VIRTUAL_KEY>>);
+// Swapped out by the file watcher when the mappings file changes
+pub type SharedMappings = Arc<RwLock<Arc<Mappings>>>;
+
+// Layers are switched by Program Change messages, the first one being the
+// default layer used at startup
+pub struct Mappings {
+    layers: Vec<Layer>,
+    programs: Vec<Option<usize>>,
+    active: AtomicUsize,
+    debounce: Duration,
+    min_key: Duration,
+    channels: Vec<Channel>,
+    middle_c_octave: i8,
+    ignore: Vec<MessageType>,
+    hold_modifier: Vec<VIRTUAL_KEY>,
+    default_key: Option<Binding>,
+    exit_chord: Vec<u8>,
+    curve: Curve,
+    overrides: Vec<Override>,
+}
+
+// A row of a later file replacing one of an earlier file
+struct Override {
+    row: Row,
+    previous: String,
+    file: String,
+}
+
+// What rows have to share to override each other, velocity windows included
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Row {
+    layer: Option<String>,
+    note: u8,
+    channel: Option<u8>,
+    velocity: (Option<u8>, Option<u8>),
+}
+
+// From `[[channel]]` blocks, with the top level transpose for the others
+#[derive(Clone, Copy)]
+struct Channel {
+    transpose: i8,
+    enabled: bool,
+}
+
+struct Layer {
+    name: Option<String>,
+    notes: Vec<Vec<Binding>>,
+    channels: Vec<Vec<Vec<Binding>>>,
+    controllers: Vec<Option<Controller>>,
+    relative: Vec<Option<Relative>>,
+    fine: Vec<Fine>,
+    bends: [Option<Bend>; 2],
+    chords: Vec<Chord>,
+    sustains: Vec<Sustain>,
+    aftertouch: Vec<Aftertouch>,
+    sysex: Vec<SysEx>,
+    transport: [Option<Vec<VIRTUAL_KEY>>; 3],
+}
+
+// What a layer is built from, either the top level of the file or a `[[layer]]`
+struct Table {
+    mapping: Vec<Located<FileMapping>>,
+    cc: Vec<Located<FileControlChange>>,
+    relative_cc: Vec<Located<FileRelativeControlChange>>,
+    cc14: Vec<Located<FileFineControlChange>>,
+    pitchbend: Option<FilePitchBend>,
+    chord: Vec<Located<FileChord>>,
+    sustain: Vec<Located<FileSustain>>,
+    aftertouch: Vec<Located<FileAftertouch>>,
+    sysex: Vec<Located<FileSysEx>>,
+    transport: Option<FileTransport>,
+}
+
+#[derive(Clone)]
+pub struct Binding {
+    pub action: Action,
+    repeat: Option<FileRepeat>,
+    pub toggle: bool,
+    pub invert: bool,
+    min_hold: Option<FileHold>,
+    pub cooldown: Option<Cooldown>,
+    pub label: Option<String>,
+    pub overlap: Overlap,
+    velocity: Option<RangeInclusive<u8>>,
+}
+
+// When the mapping last fired, shared by every note of a range so they cool
+// down together
+#[derive(Clone)]
+pub struct Cooldown {
+    duration: Duration,
+    fired: Arc<Mutex<Option<Instant>>>,
+}
+
+#[derive(Clone)]
+pub struct Controller {
+    pub keys: Vec<VIRTUAL_KEY>,
+    pub step: Option<u8>,
+}
+
+#[derive(Clone)]
+pub struct Fine {
+    pub msb: u8,
+    pub lsb: u8,
+    pub keys: Vec<VIRTUAL_KEY>,
+    pub step: Option<u16>,
+}
+
+#[derive(Clone)]
+pub struct Relative {
+    pub up: Vec<VIRTUAL_KEY>,
+    pub down: Vec<VIRTUAL_KEY>,
+    pub encoding: Encoding,
+}
+
+#[derive(Clone)]
+pub struct Chord {
+    pub notes: Vec<u8>,
+    pub keys: Vec<VIRTUAL_KEY>,
+}
+
+#[derive(Clone)]
+pub struct Sustain {
+    pub notes: Vec<u8>,
+    pub keys: Vec<VIRTUAL_KEY>,
+}
+
+// Channel pressure without a note, polyphonic key pressure with one
+pub struct Aftertouch {
+    pub note: Option<u8>,
+    pub hold: Bend,
+}
+
+pub struct SysEx {
+    prefix: Vec<u8>,
+    pub keys: Vec<VIRTUAL_KEY>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Transport {
+    Start,
+    Continue,
+    Stop,
+}
+
+impl Transport {
+    pub fn name(self) -> &'static str {
+        match self {
+            Transport::Start => "start",
+            Transport::Continue => "continue",
+            Transport::Stop => "stop",
+        }
+    }
+}
+
+// Keys held while the pitch wheel or the pressure is past a threshold in one
+// direction
+pub struct Bend {
+    pub keys: Vec<VIRTUAL_KEY>,
+    threshold: u16,
+    hysteresis: u16,
+    up: bool,
+}
+
+#[derive(Clone)]
+pub enum Action {
+    Keys(Vec<VIRTUAL_KEY>),
+    ScanCodes(Vec<u16>),
+    Tap(Vec<VIRTUAL_KEY>),
+    Mouse(Mouse),
+    Text(String),
+    Exec(Vec<String>),
+    Macro(Vec<Step>),
+    NoteOut(u8),
+    Layer(String),
+}
+
+#[derive(Clone)]
+pub enum Step {
+    Keys(Vec<VIRTUAL_KEY>),
+    Delay(Duration),
+}
 
 // http://www.music.mcgill.ca/~ich/classes/mumt306/StandardMIDIfileformat.html#BMA1_3
 // https://learn.microsoft.com/en-us/windows/win32/inputdev/virtual-key-codes
 impl Mappings {
     const LEN: usize = 128;
+    const CHANNELS: usize = 16;
 
-    fn empty() -> Self {
-        Self(vec![None; Self::LEN])
+    pub fn hardcoded() -> Self {
+        Self::from_file_mappings(FileMappings::hardcoded())
     }
 
-    pub fn hardcoded() -> Self {
-        let mut mappings = Self::empty();
-
-        // C3 -> space
-        mappings.0[48] = Some(VIRTUAL_KEY(0x20));
-        // C4 -> C
-        mappings.0[60] = Some(VIRTUAL_KEY(0x43));
-        // D4 -> D
-        mappings.0[62] = Some(VIRTUAL_KEY(0x44));
-        // E4 -> E
-        mappings.0[64] = Some(VIRTUAL_KEY(0x45));
-        // F4 -> F
-        mappings.0[65] = Some(VIRTUAL_KEY(0x46));
-        // G4 -> G
-        mappings.0[67] = Some(VIRTUAL_KEY(0x47));
+    // Commented config file with the same mappings as the hardcoded ones
+    pub fn example() -> Result<String, toml::ser::Error> {
+        let mut example = String::from(
+            "# http://www.music.mcgill.ca/~ich/classes/mumt306/StandardMIDIfileformat.html#BMA1_3\n\
+             # https://learn.microsoft.com/en-us/windows/win32/inputdev/virtual-key-codes\n",
+        );
 
-        mappings
+        for Located { value: mapping, .. } in FileMappings::hardcoded().mapping {
+            let name = notes::note_name(*mapping.note.0.start(), notes::MIDDLE_C_OCTAVE);
+            let action = match mapping.action() {
+                Some(action) => action,
+                None => continue,
+            };
+            let table = toml::to_string(&FileMappings {
+                mapping: vec![mapping.into()],
+                ..Default::default()
+            })?;
+            example.push_str(&format!("\n# {name} -> {action}\n{table}"));
+        }
+
+        Ok(example)
     }
 
+    // The format is picked from the extension, spans for validation errors are
+    // only available in TOML files
     pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Error> {
-        let file_contents = fs::read_to_string(path)?;
-        let file_mappings: FileMappings = match toml::from_str(&file_contents) {
-            Ok(file_mappings) => file_mappings,
-            Err(error) => {
-                return Err(Error::Config(MappingsError {
-                    inner: error,
-                    source: file_contents,
-                }));
+        Self::from_files(&[path])
+    }
+
+    // Each file is validated on its own and later files override the rows of
+    // earlier ones for the same notes, which the table lists
+    pub fn from_files(paths: &[impl AsRef<Path>]) -> Result<Self, Error> {
+        let mut merged: Option<FileMappings> = None;
+        let mut defined = HashMap::new();
+        let mut overrides = vec![];
+        for path in paths {
+            let path = path.as_ref();
+            let format =
+                Format::from_path(path).ok_or_else(|| Error::UnknownFormat(path.to_path_buf()))?;
+            let file_mappings = read(&fs::read_to_string(path)?, format)?;
+
+            let file = path.display().to_string();
+            let top = file_mappings.mapping.iter().map(|mapping| (None, mapping));
+            let layers = file_mappings.layer.iter().flat_map(|layer| {
+                let name = layer.value.name.clone();
+                layer
+                    .value
+                    .mapping
+                    .iter()
+                    .map(move |mapping| (name.clone(), mapping))
+            });
+            for (layer, Located { value: mapping, .. }) in top.chain(layers) {
+                for note in mapping.note.0.clone() {
+                    let row = Row {
+                        layer: layer.clone(),
+                        note,
+                        channel: mapping.channel,
+                        velocity: (mapping.velocity_min, mapping.velocity_max),
+                    };
+                    match defined.insert(row.clone(), file.clone()) {
+                        Some(previous) if previous != file => overrides.push(Override {
+                            row,
+                            previous,
+                            file: file.clone(),
+                        }),
+                        _ => {}
+                    }
+                }
             }
-        };
 
-        let mut mappings = Self::empty();
-        for mapping in file_mappings.mapping {
-            if let Some(key) = mappings.0.get_mut(mapping.note as usize) {
-                key.replace(VIRTUAL_KEY(mapping.key as u16));
+            match &mut merged {
+                Some(merged) => merged.merge(file_mappings),
+                None => merged = Some(file_mappings),
             }
         }
 
+        let mut mappings = Self::from_file_mappings(merged.unwrap_or_else(FileMappings::hardcoded));
+        mappings.overrides = overrides;
         Ok(mappings)
     }
 
-    pub fn get(&self, note: u8) -> Option<VIRTUAL_KEY> {
-        self.0.get(note as usize).copied().flatten()
+    // Same as from_file without the file, errors keep a copy of the source for
+    // miette to point into
+    pub fn parse(source: &str, format: Format) -> Result<Self, Error> {
+        Ok(Self::from_file_mappings(read(source, format)?))
+    }
+
+    fn from_file_mappings(file_mappings: FileMappings) -> Self {
+        let mut layers = vec![Layer::new(
+            None,
+            Table {
+                mapping: file_mappings.mapping,
+                cc: file_mappings.cc,
+                relative_cc: file_mappings.relative_cc,
+                cc14: file_mappings.cc14,
+                pitchbend: file_mappings.pitchbend,
+                chord: file_mappings.chord,
+                sustain: file_mappings.sustain,
+                aftertouch: file_mappings.aftertouch,
+                sysex: file_mappings.sysex,
+                transport: file_mappings.transport,
+            },
+        )];
+        let mut programs = vec![None; Self::LEN];
+        for Located { value: layer, .. } in file_mappings.layer {
+            // Programs are numbered 1-128 in the config like on hardware
+            if let Some(program) = layer
+                .program
+                .and_then(|program| (program as usize).checked_sub(1))
+                .and_then(|program| programs.get_mut(program))
+            {
+                *program = Some(layers.len());
+            }
+            layers.push(Layer::new(
+                layer.name,
+                Table {
+                    mapping: layer.mapping,
+                    cc: layer.cc,
+                    relative_cc: layer.relative_cc,
+                    cc14: layer.cc14,
+                    pitchbend: layer.pitchbend,
+                    chord: layer.chord,
+                    sustain: layer.sustain,
+                    aftertouch: layer.aftertouch,
+                    sysex: layer.sysex,
+                    transport: layer.transport,
+                },
+            ));
+        }
+
+        let transpose = file_mappings.transpose.unwrap_or(0);
+        let mut channels = vec![
+            Channel {
+                transpose,
+                enabled: true,
+            };
+            Self::CHANNELS
+        ];
+        for Located { value: channel, .. } in file_mappings.channel {
+            if let Some(settings) = (channel.channel as usize)
+                .checked_sub(1)
+                .and_then(|channel| channels.get_mut(channel))
+            {
+                *settings = Channel {
+                    transpose: channel.transpose.unwrap_or(transpose),
+                    enabled: channel.enabled.unwrap_or(true),
+                };
+            }
+        }
+
+        Self {
+            layers,
+            programs,
+            active: AtomicUsize::new(0),
+            debounce: Duration::from_millis(file_mappings.debounce_ms.unwrap_or(0)),
+            min_key: Duration::from_millis(file_mappings.min_key_ms.unwrap_or(0)),
+            channels,
+            ignore: file_mappings.ignore,
+            overrides: vec![],
+            curve: file_mappings
+                .velocity
+                .map_or_else(Curve::default, |velocity| velocity.curve),
+            hold_modifier: file_mappings
+                .hold_modifier
+                .map_or_else(Vec::new, |keys| keys.0),
+            default_key: file_mappings.default_key.map(|keys| Binding {
+                action: Action::Keys(keys.0),
+                repeat: None,
+                toggle: false,
+                invert: false,
+                min_hold: None,
+                cooldown: None,
+                label: None,
+                overlap: Overlap::default(),
+                velocity: None,
+            }),
+            exit_chord: file_mappings
+                .exit_chord
+                .map_or_else(Vec::new, |exit_chord| {
+                    exit_chord.value.iter().map(|note| note.0).collect()
+                }),
+            middle_c_octave: file_mappings
+                .middle_c_octave
+                .unwrap_or(notes::MIDDLE_C_OCTAVE),
+        }
+    }
+
+    pub fn debounce(&self) -> Duration {
+        self.debounce
+    }
+
+    // None when it's disabled
+    pub fn min_key(&self) -> Option<Duration> {
+        Some(self.min_key).filter(|min_key| !min_key.is_zero())
+    }
+
+    // For printing note names the same way they're written in the file
+    pub fn middle_c_octave(&self) -> i8 {
+        self.middle_c_octave
+    }
+
+    fn active(&self) -> &Layer {
+        &self.layers[self.active.load(Ordering::Acquire)]
+    }
+
+    // Returns the name of the layer switched to, if the program has one
+    pub fn switch_layer(&self, program: u8) -> Option<&str> {
+        let index = (*self.programs.get(program as usize)?)?;
+        self.active.store(index, Ordering::Release);
+        Some(self.layers[index].name())
+    }
+
+    pub fn program_layer(&self, program: u8) -> Option<&str> {
+        let index = (*self.programs.get(program as usize)?)?;
+        Some(self.layers[index].name())
+    }
+
+    // Reloaded mappings stay on the same layer if it still exists
+    pub fn keep_layer(&self, previous: &Mappings) {
+        let index = previous.active.load(Ordering::Acquire);
+        if index < self.layers.len() {
+            self.active.store(index, Ordering::Release);
+        }
+    }
+
+    // Layers held by modifier notes are consulted before the active one, the
+    // most recently held first
+    pub fn get<'a>(
+        &self,
+        channel: u8,
+        note: u8,
+        velocity: u8,
+        modifiers: impl Iterator<Item = &'a str>,
+    ) -> Option<&Binding> {
+        let note = self.transposed(channel, note)?;
+        let velocity = self.curve.apply(velocity);
+        modifiers
+            .filter_map(|name| self.named(name))
+            .chain([self.active()])
+            .find_map(|layer| layer.get(channel, note, velocity))
+            .or(self.default_key.as_ref())
+    }
+
+    // Empty without an exit_chord
+    pub fn exit_chord(&self) -> &[u8] {
+        &self.exit_chord
+    }
+
+    // Empty without a hold_modifier
+    pub fn hold_modifier(&self) -> &[VIRTUAL_KEY] {
+        &self.hold_modifier
+    }
+
+    pub fn ignores(&self, ty: MessageType) -> bool {
+        self.ignore.contains(&ty)
+    }
+
+    // Messages on disabled channels are dropped like ignored message types
+    pub fn enabled(&self, channel: u8) -> bool {
+        self.channels
+            .get(channel as usize)
+            .is_none_or(|channel| channel.enabled)
+    }
+
+    // Notes transposed out of range aren't mapped to anything
+    pub fn transposed(&self, channel: u8, note: u8) -> Option<u8> {
+        let transpose = self
+            .channels
+            .get(channel as usize)
+            .map_or(0, |channel| channel.transpose);
+        u8::try_from(note as i16 + transpose as i16)
+            .ok()
+            .filter(|&note| (note as usize) < Self::LEN)
+    }
+
+    pub fn chords(&self) -> &[Chord] {
+        &self.active().chords
+    }
+
+    pub fn sustains(&self) -> &[Sustain] {
+        &self.active().sustains
+    }
+
+    pub fn aftertouch(&self) -> &[Aftertouch] {
+        &self.active().aftertouch
+    }
+
+    // Whether any layer has note_out mappings
+    pub fn sends_notes(&self) -> bool {
+        self.layers.iter().any(|layer| {
+            layer
+                .notes
+                .iter()
+                .chain(layer.channels.iter().flatten())
+                .flatten()
+                .any(|binding| matches!(binding.action, Action::NoteOut(_)))
+        })
+    }
+
+    fn named(&self, name: &str) -> Option<&Layer> {
+        self.layers
+            .iter()
+            .find(|layer| layer.name.as_deref() == Some(name))
+    }
+
+    pub fn get_cc(&self, controller: u8) -> Option<&Controller> {
+        self.active().get_cc(controller)
+    }
+
+    pub fn get_relative_cc(&self, controller: u8) -> Option<&Relative> {
+        self.active().get_relative_cc(controller)
+    }
+
+    // Either controller of the pair finds it
+    pub fn get_cc14(&self, controller: u8) -> Option<&Fine> {
+        self.active()
+            .fine
+            .iter()
+            .find(|fine| fine.msb == controller || fine.lsb == controller)
+    }
+
+    // The first entry whose prefix the message starts with wins
+    pub fn get_sysex(&self, data: &[u8]) -> Option<&[VIRTUAL_KEY]> {
+        self.active()
+            .sysex
+            .iter()
+            .find(|sysex| data.starts_with(&sysex.prefix))
+            .map(|sysex| &sysex.keys[..])
+    }
+
+    pub fn get_transport(&self, transport: Transport) -> Option<&[VIRTUAL_KEY]> {
+        self.active().transport[transport as usize].as_deref()
+    }
+
+    // Up then down
+    pub fn bends(&self) -> &[Option<Bend>; 2] {
+        &self.active().bends
+    }
+
+    // Every mapped note of every layer with what it resolves to, after the
+    // names, ranges and defaults in the file were all applied
+    pub fn table(&self) -> String {
+        let mut table = String::new();
+        if !self.hold_modifier.is_empty() {
+            let keys = Action::Keys(self.hold_modifier.clone());
+            table.push_str(&format!("holding {keys} from the first note on\n"));
+        }
+        if let Some(binding) = &self.default_key {
+            table.push_str(&format!("other notes -> {}\n", binding.action));
+        }
+        if !self.exit_chord.is_empty() {
+            let notes: Vec<String> = self
+                .exit_chord
+                .iter()
+                .map(|&note| notes::note_name(note, self.middle_c_octave))
+                .collect();
+            table.push_str(&format!("exiting when {} are held\n", notes.join(", ")));
+        }
+        for Override {
+            row,
+            previous,
+            file,
+        } in &self.overrides
+        {
+            let name = notes::note_name(row.note, self.middle_c_octave);
+            table.push_str(&format!("{name} ({})", row.note));
+            if let Some(layer) = &row.layer {
+                table.push_str(&format!(" in layer {layer}"));
+            }
+            if let Some(channel) = row.channel {
+                table.push_str(&format!(" on channel {channel}"));
+            }
+            if row.velocity != (None, None) {
+                table.push_str(&format!(
+                    " velocity {}-{}",
+                    row.velocity.0.unwrap_or(0),
+                    row.velocity.1.unwrap_or(127),
+                ));
+            }
+            table.push_str(&format!(" from {previous} overridden by {file}\n"));
+        }
+        for (channel, settings) in self.channels.iter().enumerate() {
+            if !settings.enabled {
+                table.push_str(&format!("channel {} disabled\n", channel + 1));
+            } else if settings.transpose != 0 {
+                table.push_str(&format!(
+                    "channel {} transposed by {:+}\n",
+                    channel + 1,
+                    settings.transpose,
+                ));
+            }
+        }
+
+        for (index, layer) in self.layers.iter().enumerate() {
+            if !table.is_empty() {
+                table.push('\n');
+            }
+            table.push_str(&format!("layer {}", layer.name()));
+            let programs: Vec<String> = (1..)
+                .zip(&self.programs)
+                .filter(|(_, layer)| **layer == Some(index))
+                .map(|(program, _)| program.to_string())
+                .collect();
+            if !programs.is_empty() {
+                table.push_str(&format!(" (program {})", programs.join(", ")));
+            }
+            if index == 0 {
+                table.push_str(" (active at startup)");
+            }
+            table.push('\n');
+
+            // Channel-specific rows come after the shared ones they take precedence over
+            let channels = (1..)
+                .zip(&layer.channels)
+                .map(|(channel, notes)| (Some(channel), notes));
+            for (channel, notes) in iter::once((None, &layer.notes)).chain(channels) {
+                for (note, bindings) in (0..).zip(notes) {
+                    for binding in bindings {
+                        table.push_str(&self.row(note, channel, binding));
+                    }
+                }
+            }
+        }
+        table
+    }
+
+    fn row(&self, note: u8, channel: Option<u8>, binding: &Binding) -> String {
+        let name = format!("{} ({note})", notes::note_name(note, self.middle_c_octave));
+        let mut row = format!("  {name:<10}");
+        if let Some(channel) = channel {
+            row.push_str(&format!(" channel {channel}"));
+        }
+        if let Some(velocity) = &binding.velocity {
+            row.push_str(&format!(
+                " velocity {}-{}",
+                velocity.start(),
+                velocity.end(),
+            ));
+        }
+        row.push_str(&format!(" -> {}", binding.action));
+        if let Some(label) = &binding.label {
+            row.push_str(&format!(" ({label})"));
+        }
+        match binding.repeat {
+            Some(FileRepeat::Fixed(repeat)) => {
+                row.push_str(&format!(", repeated every {repeat}ms"));
+            }
+            Some(FileRepeat::Velocity(FileHold { soft, hard })) => {
+                row.push_str(&format!(", repeated every {soft}-{hard}ms by velocity"));
+            }
+            None => {}
+        }
+        if binding.toggle {
+            row.push_str(", toggled");
+        }
+        if binding.invert {
+            row.push_str(", inverted");
+        }
+        if let Some(cooldown) = &binding.cooldown {
+            row.push_str(&format!(", cooldown {}ms", cooldown.duration.as_millis()));
+        }
+        row.push('\n');
+        row
+    }
+}
+
+impl Layer {
+    fn empty(name: Option<String>) -> Self {
+        Self {
+            name,
+            notes: vec![vec![]; Mappings::LEN],
+            channels: vec![vec![vec![]; Mappings::LEN]; Mappings::CHANNELS],
+            controllers: vec![None; Mappings::LEN],
+            relative: vec![None; Mappings::LEN],
+            fine: vec![],
+            bends: [None, None],
+            chords: vec![],
+            sustains: vec![],
+            aftertouch: vec![],
+            sysex: vec![],
+            transport: [None, None, None],
+        }
+    }
+
+    fn new(name: Option<String>, table: Table) -> Self {
+        let Table {
+            mapping,
+            cc,
+            relative_cc,
+            cc14,
+            pitchbend,
+            chord,
+            sustain,
+            aftertouch,
+            sysex,
+            transport,
+        } = table;
+        let mut mappings = Self::empty(name);
+        for Located { value: mapping, .. } in mapping {
+            let notes = match mapping.channel {
+                // Channels are numbered 1-16 in the config like on hardware
+                Some(channel) => match (channel as usize)
+                    .checked_sub(1)
+                    .and_then(|channel| mappings.channels.get_mut(channel))
+                {
+                    Some(notes) => notes,
+                    None => continue,
+                },
+                None => &mut mappings.notes,
+            };
+            let velocity = match (mapping.velocity_min, mapping.velocity_max) {
+                (None, None) => None,
+                (min, max) => Some(min.unwrap_or(0)..=max.unwrap_or(127)),
+            };
+
+            let cooldown = mapping.cooldown_ms.map(|cooldown| Cooldown {
+                duration: Duration::from_millis(cooldown),
+                fired: Arc::default(),
+            });
+
+            for (index, note) in mapping.note.0.clone().enumerate() {
+                let bindings = match notes.get_mut(note as usize) {
+                    Some(bindings) => bindings,
+                    None => continue,
+                };
+                // Later rows override earlier ones with the same velocity window,
+                // which only happens across files
+                bindings.retain(|binding| binding.velocity != velocity);
+                // Mappings without exactly one action are rejected by validation
+                let action = match mapping.action_at(index) {
+                    Some(action) => action,
+                    None => continue,
+                };
+                bindings.push(Binding {
+                    action,
+                    repeat: mapping.repeat_ms,
+                    toggle: mapping.toggle,
+                    invert: mapping.invert,
+                    min_hold: mapping.min_hold_ms,
+                    cooldown: cooldown.clone(),
+                    label: mapping.label.clone(),
+                    overlap: mapping.macro_overlap.unwrap_or_default(),
+                    velocity: velocity.clone(),
+                });
+            }
+        }
+        for Located { value: mapping, .. } in cc {
+            if let Some(controller) = mappings.controllers.get_mut(mapping.controller as usize) {
+                controller.replace(Controller {
+                    keys: mapping.key.0,
+                    step: mapping.step,
+                });
+            }
+        }
+        for Located { value: mapping, .. } in relative_cc {
+            if let Some(relative) = mappings.relative.get_mut(mapping.controller as usize) {
+                relative.replace(Relative {
+                    up: mapping.up.0,
+                    down: mapping.down.0,
+                    encoding: mapping.encoding,
+                });
+            }
+        }
+        for Located { value: pair, .. } in cc14 {
+            mappings.fine.push(Fine {
+                msb: pair.msb,
+                lsb: pair.lsb(),
+                keys: pair.key.0,
+                step: pair.step,
+            });
+        }
+        if let Some(pitch_bend) = pitchbend {
+            let hysteresis = pitch_bend.hysteresis.unwrap_or(Bend::DEFAULT_HYSTERESIS);
+            let bend = |bend: Located<FileBend>, up| Bend {
+                keys: bend.value.key.0,
+                threshold: bend.value.threshold,
+                hysteresis,
+                up,
+            };
+            mappings.bends = [
+                pitch_bend.up.map(|up| bend(up, true)),
+                pitch_bend.down.map(|down| bend(down, false)),
+            ];
+        }
+
+        mappings.chords = chord
+            .into_iter()
+            .map(|Located { value: chord, .. }| Chord {
+                notes: chord.notes.iter().map(|note| note.0).collect(),
+                keys: chord.key.0,
+            })
+            .collect();
+        mappings.sustains = sustain
+            .into_iter()
+            .map(|Located { value: sustain, .. }| Sustain {
+                notes: sustain.notes.iter().map(|note| note.0).collect(),
+                keys: sustain.key.0,
+            })
+            .collect();
+        mappings.aftertouch = aftertouch
+            .into_iter()
+            .map(
+                |Located {
+                     value: aftertouch, ..
+                 }| Aftertouch {
+                    note: aftertouch.note.map(|note| note.0),
+                    hold: Bend {
+                        keys: aftertouch.key.0,
+                        threshold: aftertouch.threshold.into(),
+                        hysteresis: aftertouch
+                            .hysteresis
+                            .map_or(Aftertouch::DEFAULT_HYSTERESIS, Into::into),
+                        up: true,
+                    },
+                },
+            )
+            .collect();
+        mappings.sysex = sysex
+            .into_iter()
+            .map(|Located { value: sysex, .. }| {
+                let mut prefix = sysex.bytes;
+                prefix.truncate(sysex.prefix_len.unwrap_or(prefix.len()));
+                SysEx {
+                    prefix,
+                    keys: sysex.key.0,
+                }
+            })
+            .collect();
+        if let Some(transport) = transport {
+            let keys = [transport.start, transport.resume, transport.stop];
+            mappings.transport = keys.map(|keys| keys.map(|keys| keys.0));
+        }
+
+        mappings
+    }
+
+    // Channel-specific mappings take precedence over the shared ones
+    fn get(&self, channel: u8, note: u8, velocity: u8) -> Option<&Binding> {
+        self.channels
+            .get(channel as usize)
+            .and_then(|notes| Self::resolve(notes, note, velocity))
+            .or_else(|| Self::resolve(&self.notes, note, velocity))
+    }
+
+    fn resolve(notes: &[Vec<Binding>], note: u8, velocity: u8) -> Option<&Binding> {
+        let bindings = notes.get(note as usize)?;
+        bindings
+            .iter()
+            .find(|binding| {
+                binding
+                    .velocity
+                    .as_ref()
+                    .is_some_and(|window| window.contains(&velocity))
+            })
+            .or_else(|| bindings.iter().find(|binding| binding.velocity.is_none()))
+    }
+
+    fn get_cc(&self, controller: u8) -> Option<&Controller> {
+        self.controllers.get(controller as usize)?.as_ref()
+    }
+
+    fn get_relative_cc(&self, controller: u8) -> Option<&Relative> {
+        self.relative.get(controller as usize)?.as_ref()
+    }
+
+    fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or("default")
+    }
+}
+
+impl Binding {
+    // Scales linearly with velocity
+    pub fn min_hold(&self, velocity: u8) -> Option<Duration> {
+        Some(scale(self.min_hold?, velocity))
+    }
+
+    // How often the action is repeated for a hit this hard
+    pub fn repeat(&self, velocity: u8) -> Option<Duration> {
+        match self.repeat? {
+            FileRepeat::Fixed(interval) => Some(Duration::from_millis(interval)),
+            FileRepeat::Velocity(interval) => Some(scale(interval, velocity)),
+        }
     }
 }
 
-#[derive(Deserialize)]
-struct FileMappings {
-    mapping: Vec<FileMapping>,
+// Linearly from soft at velocity 0 to hard at 127
+fn scale(FileHold { soft, hard }: FileHold, velocity: u8) -> Duration {
+    let velocity = velocity.min(127) as i128;
+    let millis = soft as i128 + (hard as i128 - soft as i128) * velocity / 127;
+    Duration::from_millis(millis as u64)
 }
 
-#[derive(Deserialize)]
-struct FileMapping {
-    note: u8,
-    key: u8,
+impl Cooldown {
+    // Whether the mapping can fire now, starting the cooldown again if so
+    pub fn ready(&self) -> bool {
+        let now = Instant::now();
+        let mut fired = self.fired.lock().unwrap();
+        match *fired {
+            Some(last) if now.duration_since(last) < self.duration => false,
+            _ => {
+                *fired = Some(now);
+                true
+            }
+        }
+    }
+}
+
+impl Controller {
+    // Sustain pedal convention without a step, 0-63 is off and 64-127 is on
+    pub fn bucket(&self, value: u8) -> u8 {
+        value / self.step.unwrap_or(64).max(1)
+    }
+}
+
+impl Fine {
+    // Like a 7-bit controller, 0-8191 is off and 8192-16383 is on without a step
+    pub fn bucket(&self, value: u16) -> u16 {
+        value / self.step.unwrap_or(8192).max(1)
+    }
+}
+
+impl Relative {
+    // The keys for the direction the value moves in and how many times to tap
+    // them, nothing when it doesn't move
+    pub fn taps(&self, value: u8) -> Option<(&[VIRTUAL_KEY], u8)> {
+        let delta = self.encoding.delta(value);
+        let keys = match delta {
+            0 => return None,
+            1.. => &self.up,
+            _ => &self.down,
+        };
+        Some((keys, delta.unsigned_abs()))
+    }
+}
+
+impl Aftertouch {
+    const DEFAULT_HYSTERESIS: u16 = 4;
+}
+
+impl Bend {
+    const DEFAULT_HYSTERESIS: u16 = 256;
+
+    pub fn beyond(&self, value: u16) -> bool {
+        if self.up {
+            value > self.threshold
+        } else {
+            value < self.threshold
+        }
+    }
+
+    // The new state when the value crosses the threshold, releasing only once
+    // it's back by more than the hysteresis so a wobbly wheel doesn't chatter
+    pub fn transition(&self, held: bool, value: u16) -> Option<bool> {
+        let released = if self.up {
+            value < self.threshold.saturating_sub(self.hysteresis)
+        } else {
+            value > self.threshold.saturating_add(self.hysteresis)
+        };
+        match held {
+            false if self.beyond(value) => Some(true),
+            true if released => Some(false),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Keys(keys) => write_keys(f, keys),
+            Action::ScanCodes(codes) => {
+                f.write_str("scancode ")?;
+                for (i, code) in codes.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str("+")?;
+                    }
+                    write!(f, "{code:#04X}")?;
+                }
+                Ok(())
+            }
+            Action::Tap(keys) => {
+                f.write_str("tap")?;
+                for (i, &key) in keys.iter().enumerate() {
+                    f.write_str(if i > 0 { ", " } else { " " })?;
+                    write_keys(f, &[key])?;
+                }
+                Ok(())
+            }
+            Action::Mouse(Mouse::Button(button)) => {
+                let button = match button {
+                    MouseButton::Left => "left",
+                    MouseButton::Right => "right",
+                    MouseButton::Middle => "middle",
+                };
+                write!(f, "{button} mouse button")
+            }
+            Action::Mouse(Mouse::Move { by: (x, y) }) => write!(f, "mouse move by {x}, {y}"),
+            Action::Text(text) => write!(f, "{text:?}"),
+            Action::Exec(command) => write!(f, "run {}", command.join(" ")),
+            Action::Macro(steps) => {
+                f.write_str("macro")?;
+                for (i, step) in steps.iter().enumerate() {
+                    f.write_str(if i > 0 { ", " } else { " " })?;
+                    match step {
+                        Step::Keys(keys) => write_keys(f, keys)?,
+                        Step::Delay(delay) => write!(f, "{}ms", delay.as_millis())?,
+                    }
+                }
+                Ok(())
+            }
+            Action::NoteOut(note) => write!(f, "send note {note}"),
+            Action::Layer(name) => write!(f, "layer {name}"),
+        }
+    }
+}
+
+fn write_keys(f: &mut fmt::Formatter<'_>, keys: &[VIRTUAL_KEY]) -> fmt::Result {
+    for (i, &key) in keys.iter().enumerate() {
+        if i > 0 {
+            f.write_str("+")?;
+        }
+        match keys::key_name(key) {
+            Some(name) => f.write_str(&name)?,
+            None => write!(f, "{:#04X}", key.0)?,
+        }
+    }
+    Ok(())
+}
+
+// Mappings written in TOML, like the contents of an m2k.toml
+impl FromStr for Mappings {
+    type Err = Error;
+
+    fn from_str(source: &str) -> Result<Self, Error> {
+        Self::parse(source, Format::Toml)
+    }
 }
 
 #[derive(Debug)]
 pub struct MappingsError {
-    inner: toml::de::Error,
+    labels: Vec<LabeledSpan>,
+    help: Option<String>,
     source: String,
 }
 
+impl MappingsError {
+    fn parse(error: toml::de::Error, source: &str) -> Self {
+        let labels = error
+            .span()
+            .map(|span| LabeledSpan::at(span, error.message()))
+            .into_iter()
+            .collect();
+        Self {
+            labels,
+            help: None,
+            source: source.to_owned(),
+        }
+    }
+
+    fn at(index: Option<usize>, message: String, source: &str) -> Self {
+        let labels = index
+            .map(|index| LabeledSpan::at_offset(index.min(source.len()), message))
+            .into_iter()
+            .collect();
+        Self {
+            labels,
+            help: None,
+            source: source.to_owned(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "toml" => Some(Format::Toml),
+            "json" => Some(Format::Json),
+            "yaml" | "yml" => Some(Format::Yaml),
+            _ => None,
+        }
+    }
+}
+
+fn read(source: &str, format: Format) -> Result<FileMappings, Error> {
+    // Note names depend on the octave convention, syntax errors are reported
+    // by the full parse below
+    let prelude = parse::<Prelude>(format, source).ok();
+    let context = Context {
+        spans: format == Format::Toml,
+        middle_c_octave: prelude
+            .and_then(|prelude| prelude.middle_c_octave)
+            .unwrap_or(notes::MIDDLE_C_OCTAVE),
+    };
+    let file_mappings: FileMappings =
+        config::with_context(context, || parse(format, source)).map_err(Error::Config)?;
+
+    if let Err(invalid) = file_mappings.validate() {
+        return Err(Error::Config(MappingsError {
+            labels: invalid.labels,
+            help: Some(invalid.help.into()),
+            source: source.to_owned(),
+        }));
+    }
+    Ok(file_mappings)
+}
+
+fn parse<T: DeserializeOwned>(format: Format, source: &str) -> Result<T, MappingsError> {
+    match format {
+        Format::Toml => toml::from_str(source).map_err(|error| MappingsError::parse(error, source)),
+        Format::Json => serde_json::from_str(source).map_err(|error| {
+            let index = index(source, error.line(), error.column());
+            MappingsError::at(Some(index), error.to_string(), source)
+        }),
+        Format::Yaml => serde_yaml::from_str(source).map_err(|error| {
+            let index = error.location().map(|location| location.index());
+            MappingsError::at(index, error.to_string(), source)
+        }),
+    }
+}
+
+// Byte index of a 1-based line and column
+fn index(source: &str, line: usize, column: usize) -> usize {
+    let line_start: usize = source
+        .split_inclusive('\n')
+        .take(line.saturating_sub(1))
+        .map(str::len)
+        .sum();
+    line_start + column.saturating_sub(1)
+}
+
 impl std::fmt::Display for MappingsError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str("Configuration error")
@@ -96,14 +1172,54 @@ impl Diagnostic for MappingsError {
         Some(&self.source)
     }
 
+    fn help<'a>(&'a self) -> Option<Box<dyn std::fmt::Display + 'a>> {
+        self.help
+            .as_ref()
+            .map(|help| Box::new(help) as Box<dyn std::fmt::Display>)
+    }
+
     fn labels(&self) -> Option<Box<dyn Iterator<Item = LabeledSpan> + '_>> {
-        if let Some(span) = self.inner.span() {
-            Some(Box::new(iter::once(LabeledSpan::at(
-                span,
-                self.inner.message(),
-            ))))
-        } else {
+        if self.labels.is_empty() {
             None
+        } else {
+            Some(Box::new(self.labels.iter().cloned()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Where the first label points in the source
+    fn offset(source: &str, format: Format) -> usize {
+        match Mappings::parse(source, format) {
+            Err(Error::Config(error)) => error.labels[0].offset(),
+            _ => panic!("accepted {source}"),
         }
     }
+
+    #[test]
+    fn syntax_errors_point_into_the_source() {
+        let json = "{\"mapping\": [{\"note\": 60, \"key\": \"f1\"},]}";
+        assert_eq!(&json[offset(json, Format::Json)..], "]}");
+        let yaml = "mapping:\n  - note: 60\n    key: [f1\n";
+        assert!(offset(yaml, Format::Yaml) > 0);
+    }
+
+    #[test]
+    fn note_out_in_layers() {
+        let source = "[[layer]]\nname = \"out\"\n\n[[layer.mapping]]\nnote = 60\nnote_out = 36\n";
+        assert!(source.parse::<Mappings>().unwrap().sends_notes());
+        assert!(!Mappings::hardcoded().sends_notes());
+    }
+
+    #[test]
+    fn validation_errors_in_any_format() {
+        let json = r#"{"mapping": [{"note": 60, "key": "f1", "channel": 17}]}"#;
+        assert!(matches!(
+            Mappings::parse(json, Format::Json),
+            Err(Error::Config(MappingsError { help: Some(_), .. })),
+        ));
+    }
 }
@@ -0,0 +1,119 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crossterm::{
+    cursor::{Hide, MoveTo, Show},
+    queue,
+    style::Print,
+    terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen},
+};
+
+use crate::{handler::Event, notes};
+
+const RECENT: usize = 16;
+
+// Whether the terminal is currently showing the monitor, so it can be restored
+// from anywhere when exiting
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+// Live view of held notes and recent messages redrawn on every message, in
+// place of the scrolling debug output
+#[derive(Clone)]
+pub struct Monitor(Arc<Mutex<State>>);
+
+struct State {
+    held: BTreeMap<(u8, u8), String>,
+    recent: VecDeque<String>,
+    middle_c_octave: i8,
+}
+
+impl Monitor {
+    pub fn start() -> io::Result<Self> {
+        queue!(io::stdout(), EnterAlternateScreen, Hide)?;
+        ACTIVE.store(true, Ordering::Release);
+
+        let monitor = Self(Arc::new(Mutex::new(State {
+            held: BTreeMap::new(),
+            recent: VecDeque::new(),
+            middle_c_octave: notes::MIDDLE_C_OCTAVE,
+        })));
+        monitor.0.lock().unwrap().draw()?;
+        Ok(monitor)
+    }
+
+    pub fn event(
+        &self,
+        event: &Event,
+        mapped: Option<&str>,
+        middle_c_octave: i8,
+    ) -> io::Result<()> {
+        let mapped = mapped.unwrap_or("unmapped");
+        let mut state = self.0.lock().unwrap();
+        state.middle_c_octave = middle_c_octave;
+        match *event {
+            Event::NoteOn { channel, note, .. } => {
+                state.held.insert((channel, note), mapped.to_owned());
+            }
+            Event::NoteOff { channel, note } => {
+                state.held.remove(&(channel, note));
+            }
+            _ => {}
+        }
+
+        if state.recent.len() == RECENT {
+            state.recent.pop_back();
+        }
+        let line = format!("{} -> {mapped}", event.display(middle_c_octave));
+        state.recent.push_front(line);
+        state.draw()
+    }
+
+    pub fn stop(&self) -> io::Result<()> {
+        restore()
+    }
+}
+
+// Leaves the monitor if it's shown, doing nothing otherwise
+pub fn restore() -> io::Result<()> {
+    if ACTIVE.swap(false, Ordering::AcqRel) {
+        queue!(io::stdout(), Show, LeaveAlternateScreen)?;
+        io::stdout().flush()?;
+    }
+    Ok(())
+}
+
+impl State {
+    fn draw(&self) -> io::Result<()> {
+        let (_, rows) = terminal::size().unwrap_or((80, 24));
+        let mut lines = vec!["m2k monitor, Ctrl+C to exit".to_owned(), String::new()];
+
+        lines.push("Held".to_owned());
+        if self.held.is_empty() {
+            lines.push("  nothing".to_owned());
+        }
+        for ((channel, note), mapped) in &self.held {
+            lines.push(format!(
+                "  channel {:<2}  {:<4} ({note:>3})  -> {mapped}",
+                channel + 1,
+                notes::note_name(*note, self.middle_c_octave),
+            ));
+        }
+
+        lines.push(String::new());
+        lines.push("Recent".to_owned());
+        lines.extend(self.recent.iter().map(|line| format!("  {line}")));
+
+        let mut stdout = io::stdout().lock();
+        queue!(stdout, MoveTo(0, 0), Clear(ClearType::All))?;
+        for (row, line) in lines.iter().take(rows as usize).enumerate() {
+            queue!(stdout, MoveTo(0, row as u16), Print(line))?;
+        }
+        stdout.flush()
+    }
+}
@@ -0,0 +1,72 @@
+// Scientific pitch notation, where middle C (60) is C4
+pub const MIDDLE_C_OCTAVE: i8 = 4;
+
+// The octave of middle C differs between manufacturers
+pub fn parse_note_name(name: &str, middle_c_octave: i8) -> Option<u8> {
+    let mut chars = name.chars();
+
+    let semitone: i32 = match chars.next()?.to_ascii_uppercase() {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+
+    let rest = chars.as_str();
+    let (accidental, octave) = match rest.chars().next()? {
+        '#' => (1, &rest[1..]),
+        'b' => (-1, &rest[1..]),
+        _ => (0, rest),
+    };
+
+    // Reject things like "C+4" which the integer parser would otherwise accept
+    if !octave.starts_with(|c: char| c == '-' || c.is_ascii_digit()) {
+        return None;
+    }
+    let octave: i32 = octave.parse().ok()?;
+
+    let note = (octave - middle_c_octave as i32 + 5) * 12 + semitone + accidental;
+    u8::try_from(note).ok().filter(|&note| note < 128)
+}
+
+// The opposite of parse_note_name, with sharps for black keys
+pub fn note_name(note: u8, middle_c_octave: i8) -> String {
+    const NAMES: [&str; 12] = [
+        "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+    ];
+    let octave = note as i32 / 12 - 5 + middle_c_octave as i32;
+    format!("{}{octave}", NAMES[note as usize % 12])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn note_names() {
+        assert_eq!(parse_note_name("C4", MIDDLE_C_OCTAVE), Some(60));
+        assert_eq!(parse_note_name("c#4", MIDDLE_C_OCTAVE), Some(61));
+        assert_eq!(parse_note_name("Db4", MIDDLE_C_OCTAVE), Some(61));
+        assert_eq!(parse_note_name("C3", 3), Some(60));
+        assert_eq!(parse_note_name("C-1", MIDDLE_C_OCTAVE), Some(0));
+        assert_eq!(parse_note_name("G9", MIDDLE_C_OCTAVE), Some(127));
+    }
+
+    #[test]
+    fn invalid_note_names() {
+        for name in ["", "H4", "C", "C+4", "Cb-1", "G#9", "C4x"] {
+            assert_eq!(parse_note_name(name, MIDDLE_C_OCTAVE), None, "{name}");
+        }
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for note in 0..128 {
+            assert_eq!(parse_note_name(&note_name(note, 3), 3), Some(note));
+        }
+    }
+}
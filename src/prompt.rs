@@ -0,0 +1,51 @@
+use m2k::Error;
+use windows::core::HSTRING;
+
+// Questions asked on the terminal, which builds without the interactive feature
+// can't ask and need flags for instead
+pub const INTERACTIVE: bool = cfg!(feature = "interactive");
+
+#[cfg(feature = "interactive")]
+pub fn device(names: &[HSTRING], color: bool) -> Result<Option<usize>, Error> {
+    let index = dialoguer::FuzzySelect::with_theme(&*theme(color))
+        .with_prompt("MIDI device")
+        .items(names)
+        .interact()
+        .map_err(interrupted)?;
+    Ok(Some(index))
+}
+
+#[cfg(feature = "interactive")]
+pub fn debug(default: bool, color: bool) -> Result<Option<bool>, Error> {
+    let debug = dialoguer::Confirm::with_theme(&*theme(color))
+        .with_prompt("Print received messages")
+        .default(default)
+        .interact()
+        .map_err(interrupted)?;
+    Ok(Some(debug))
+}
+
+// Ctrl+C while asking ends up here as well as the terminal going away
+#[cfg(feature = "interactive")]
+fn interrupted(dialoguer::Error::IO(error): dialoguer::Error) -> Error {
+    Error::Prompt(error)
+}
+
+#[cfg(feature = "interactive")]
+fn theme(color: bool) -> Box<dyn dialoguer::theme::Theme> {
+    if color {
+        Box::new(dialoguer::theme::ColorfulTheme::default())
+    } else {
+        Box::new(dialoguer::theme::SimpleTheme)
+    }
+}
+
+#[cfg(not(feature = "interactive"))]
+pub fn device(_names: &[HSTRING], _color: bool) -> Result<Option<usize>, Error> {
+    Ok(None)
+}
+
+#[cfg(not(feature = "interactive"))]
+pub fn debug(_default: bool, _color: bool) -> Result<Option<bool>, Error> {
+    Ok(None)
+}
@@ -0,0 +1,166 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::Overlap,
+    input,
+    mappings::{Action, Step},
+    schedule::{self, Job, Tag},
+};
+
+// Keys tapped for held notes or macros playing on the scheduler, keyed by
+// channel and note, scheduled with the tag of the listener they belong to
+#[derive(Default)]
+pub struct Repeats {
+    playing: Mutex<HashMap<(u8, u8), Repeat>>,
+    tag: Tag,
+}
+
+struct Repeat {
+    stop: Arc<AtomicBool>,
+    queue: Queue,
+}
+
+// Macros waiting for the one playing to be done, None once it stopped taking them
+type Queue = Arc<Mutex<Option<VecDeque<Vec<Step>>>>>;
+
+impl Repeats {
+    pub fn new(tag: Tag) -> Self {
+        Self {
+            playing: Mutex::default(),
+            tag,
+        }
+    }
+
+    pub fn tag(&self) -> Tag {
+        self.tag
+    }
+
+    pub fn start(&self, channel: u8, note: u8, action: Action, interval: Duration) {
+        self.spawn(channel, note, Queue::default(), move |stop| {
+            repeat(action, interval, stop)
+        });
+    }
+
+    // Macros keep playing after the note off, only replaying the note or
+    // stopping everything interrupts them. Replaying the note while the macro
+    // is still playing does what the overlap says.
+    pub fn play(&self, channel: u8, note: u8, steps: Vec<Step>, overlap: Overlap) {
+        if let Some(playing) = self.playing.lock().unwrap().get(&(channel, note)) {
+            match overlap {
+                Overlap::Ignore if playing.queue.lock().unwrap().is_some() => return,
+                Overlap::Queue => {
+                    if let Some(queue) = playing.queue.lock().unwrap().as_mut() {
+                        queue.push_back(steps);
+                        return;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let queue = Queue::new(Mutex::new(Some(VecDeque::new())));
+        self.spawn(channel, note, queue.clone(), move |stop| {
+            play(steps, 0, queue, stop)
+        });
+    }
+
+    fn spawn(&self, channel: u8, note: u8, queue: Queue, job: impl FnOnce(Arc<AtomicBool>) -> Job) {
+        let stop = Arc::new(AtomicBool::new(false));
+        let previous = self.playing.lock().unwrap().insert(
+            (channel, note),
+            Repeat {
+                stop: stop.clone(),
+                queue,
+            },
+        );
+        // A note retriggered without a note off replaces its previous repeat
+        if let Some(previous) = previous {
+            previous.stop();
+        }
+        schedule::at(Instant::now(), self.tag, job(stop));
+    }
+
+    // Returns whether the note was repeating
+    pub fn stop(&self, channel: u8, note: u8) -> bool {
+        let repeat = self.playing.lock().unwrap().remove(&(channel, note));
+        match repeat {
+            Some(repeat) => {
+                repeat.stop();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn stop_all(&self) {
+        let repeats: Vec<Repeat> = self
+            .playing
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(_, r)| r)
+            .collect();
+        for repeat in repeats {
+            repeat.stop();
+        }
+    }
+}
+
+impl Repeat {
+    fn stop(self) {
+        schedule::cancel(&self.stop);
+    }
+}
+
+// Taps the keys and schedules the next tap an interval after this one was due
+fn repeat(action: Action, interval: Duration, stop: Arc<AtomicBool>) -> Job {
+    Job::new(move |due| {
+        if stop.load(Ordering::Acquire) {
+            return None;
+        }
+        let mut inputs = input::press(&action);
+        inputs.extend(input::release(&action));
+        input::send(inputs);
+        Some((due + interval, repeat(action, interval, stop)))
+    })
+}
+
+// Plays steps up to the next delay and schedules the rest for after it, going
+// on with the queued macros once the last step is played
+fn play(mut steps: Vec<Step>, mut index: usize, queue: Queue, stop: Arc<AtomicBool>) -> Job {
+    Job::new(move |due| loop {
+        if stop.load(Ordering::Acquire) {
+            *queue.lock().unwrap() = None;
+            return None;
+        }
+        match steps.get(index) {
+            Some(Step::Keys(keys)) => {
+                input::send(input::tap(keys));
+                index += 1;
+            }
+            Some(&Step::Delay(delay)) => {
+                return Some((due + delay, play(steps, index + 1, queue, stop)));
+            }
+            None => {
+                let mut queued = queue.lock().unwrap();
+                match queued.as_mut().and_then(VecDeque::pop_front) {
+                    Some(next) => {
+                        steps = next;
+                        index = 0;
+                    }
+                    None => {
+                        *queued = None;
+                        return None;
+                    }
+                }
+            }
+        }
+    })
+}
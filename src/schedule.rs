@@ -0,0 +1,165 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BTreeMap, BinaryHeap},
+    sync::{
+        atomic::{self, AtomicBool, AtomicU64},
+        Condvar, Mutex, Once,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+// Everything timed, from repeats and macro delays to releases held back for a
+// minimum hold time, runs on a single thread in the order it's due instead of a
+// thread per note. Instants come from QueryPerformanceCounter on Windows.
+static PENDING: Mutex<Pending> = Mutex::new(Pending {
+    heap: BinaryHeap::new(),
+    next: 0,
+    generations: BTreeMap::new(),
+});
+static TAGS: AtomicU64 = AtomicU64::new(0);
+static WAKE: Condvar = Condvar::new();
+// Held while a job runs so cancelling can wait for the one already running
+static RUNNING: Mutex<()> = Mutex::new(());
+static START: Once = Once::new();
+
+// Called with the time it was due at, returning what to run next and when
+pub struct Job(Box<dyn FnOnce(Instant) -> Option<(Instant, Job)> + Send>);
+
+// Which listener a job belongs to, so stopping one leaves the others' jobs alone
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Tag(u64);
+
+struct Pending {
+    heap: BinaryHeap<Reverse<Due>>,
+    // Jobs due at the same time run in the order they were added
+    next: u64,
+    // Bumped by clearing a tag, so its jobs running meanwhile don't add their next
+    // one back
+    generations: BTreeMap<Tag, u64>,
+}
+
+struct Due {
+    at: Instant,
+    order: u64,
+    tag: Tag,
+    job: Job,
+}
+
+impl Job {
+    pub fn new(job: impl FnOnce(Instant) -> Option<(Instant, Job)> + Send + 'static) -> Self {
+        Self(Box::new(job))
+    }
+}
+
+impl Tag {
+    pub fn new() -> Self {
+        Self(TAGS.fetch_add(1, atomic::Ordering::Relaxed))
+    }
+}
+
+impl Default for Tag {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pending {
+    fn push(&mut self, at: Instant, tag: Tag, job: Job) {
+        let order = self.next;
+        self.next += 1;
+        self.heap.push(Reverse(Due {
+            at,
+            order,
+            tag,
+            job,
+        }));
+    }
+
+    fn generation(&self, tag: Tag) -> u64 {
+        self.generations.get(&tag).copied().unwrap_or(0)
+    }
+}
+
+impl PartialEq for Due {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.order) == (other.at, other.order)
+    }
+}
+
+impl Eq for Due {}
+
+impl PartialOrd for Due {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Due {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.at, self.order).cmp(&(other.at, other.order))
+    }
+}
+
+// The thread is started by the first job
+pub fn at(at: Instant, tag: Tag, job: Job) {
+    START.call_once(|| {
+        thread::spawn(run);
+    });
+    PENDING.lock().unwrap().push(at, tag, job);
+    WAKE.notify_one();
+}
+
+pub fn after(delay: Duration, tag: Tag, job: Job) {
+    at(Instant::now() + delay, tag, job);
+}
+
+// Jobs check their flag before doing anything, so once this returns the job
+// won't send anything anymore. Never called from a job since it would wait for
+// itself.
+pub fn cancel(stop: &AtomicBool) {
+    stop.store(true, atomic::Ordering::Release);
+    drop(RUNNING.lock().unwrap());
+}
+
+// Drops everything the tag still has pending when shutting down, after waiting
+// for the job running if there is one
+pub fn clear(tag: Tag) {
+    let mut pending = PENDING.lock().unwrap();
+    pending.heap.retain(|Reverse(due)| due.tag != tag);
+    *pending.generations.entry(tag).or_default() += 1;
+    drop(pending);
+    drop(RUNNING.lock().unwrap());
+}
+
+fn run() {
+    let mut pending = PENDING.lock().unwrap();
+    loop {
+        let at = match pending.heap.peek() {
+            Some(Reverse(due)) => due.at,
+            None => {
+                pending = WAKE.wait(pending).unwrap();
+                continue;
+            }
+        };
+        // Waking up early or for a sooner job just goes around again
+        let now = Instant::now();
+        if at > now {
+            pending = WAKE.wait_timeout(pending, at - now).unwrap().0;
+            continue;
+        }
+
+        let Reverse(due) = pending.heap.pop().unwrap();
+        let generation = pending.generation(due.tag);
+        drop(pending);
+        let running = RUNNING.lock().unwrap();
+        let next = (due.job.0)(due.at);
+        drop(running);
+
+        pending = PENDING.lock().unwrap();
+        if let (Some((at, job)), true) = (next, pending.generation(due.tag) == generation) {
+            // Running late pushes the next one back instead of catching up in a burst
+            pending.push(at.max(Instant::now()), due.tag, job);
+        }
+    }
+}
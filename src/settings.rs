@@ -0,0 +1,46 @@
+use std::{fs, path::PathBuf};
+
+use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
+
+use m2k::Error;
+
+// Choices from the last launch, used to skip the prompts on the next one
+#[derive(Default, Deserialize, Serialize)]
+pub struct Settings {
+    pub device: Option<String>,
+    #[serde(default)]
+    pub debug: bool,
+}
+
+pub fn config_dir() -> Option<PathBuf> {
+    let directories = ProjectDirs::from("", "", "m2k")?;
+    Some(directories.config_dir().to_path_buf())
+}
+
+impl Settings {
+    fn path() -> Option<PathBuf> {
+        config_dir().map(|directory| directory.join("settings.toml"))
+    }
+
+    // Missing or unreadable settings just mean prompting again
+    pub fn load() -> Self {
+        Self::path()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|contents| toml::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        let path = match Self::path() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+        fs::write(path, toml::to_string(self)?)?;
+        Ok(())
+    }
+}
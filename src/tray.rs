@@ -0,0 +1,201 @@
+use std::{cell::RefCell, sync::mpsc, thread};
+
+use windows::{
+    core::{w, PCWSTR},
+    Win32::{
+        Foundation::{HWND, LPARAM, LRESULT, POINT, WPARAM},
+        System::{Console::FreeConsole, LibraryLoader::GetModuleHandleW},
+        UI::{
+            Shell::{
+                Shell_NotifyIconW, NIF_ICON, NIF_MESSAGE, NIF_TIP, NIM_ADD, NIM_DELETE,
+                NOTIFYICONDATAW,
+            },
+            WindowsAndMessaging::{
+                AppendMenuW, CreatePopupMenu, CreateWindowExW, DefWindowProcW, DestroyMenu,
+                DestroyWindow, DispatchMessageW, GetCursorPos, GetMessageW, LoadIconW,
+                PostMessageW, PostQuitMessage, RegisterClassW, SetForegroundWindow, TrackPopupMenu,
+                TranslateMessage, IDI_APPLICATION, MF_GRAYED, MF_SEPARATOR, MF_STRING, MSG,
+                TPM_RIGHTBUTTON, WINDOW_EX_STYLE, WINDOW_STYLE, WM_APP, WM_CLOSE, WM_COMMAND,
+                WM_DESTROY, WM_LBUTTONUP, WM_RBUTTONUP, WNDCLASSW,
+            },
+        },
+    },
+};
+
+use m2k::{report_error, Error};
+
+use crate::Exit;
+
+// Sent by the shell when something happens to the icon
+const WM_TRAY: u32 = WM_APP + 1;
+const QUIT: usize = 1;
+
+// Everything the window procedure needs, which runs on the tray thread
+struct State {
+    devices: Vec<u16>,
+    exit: Exit,
+}
+
+thread_local! {
+    static STATE: RefCell<Option<State>> = const { RefCell::new(None) };
+}
+
+// Icon in the notification area with the devices being listened to and a quit
+// item going through the same shutdown as Ctrl+C
+pub struct Tray(HWND);
+
+impl Tray {
+    pub fn spawn(devices: String, exit: Exit) -> Result<Self, Error> {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            STATE.with(|state| {
+                *state.borrow_mut() = Some(State {
+                    devices: wide(&devices),
+                    exit,
+                })
+            });
+            let window = unsafe { create(&devices) };
+            let created = window.is_ok();
+            let _ = sender.send(window);
+            if created {
+                unsafe { run() };
+            }
+        });
+
+        let window = receiver.recv().unwrap()?;
+        // The console would otherwise stay open next to the icon
+        let _ = unsafe { FreeConsole() };
+        Ok(Self(window))
+    }
+}
+
+// The icon is removed right away since the process usually exits right after,
+// before the tray thread gets to it
+impl Drop for Tray {
+    fn drop(&mut self) {
+        unsafe {
+            Shell_NotifyIconW(NIM_DELETE, &icon(self.0));
+            let _ = PostMessageW(self.0, WM_CLOSE, WPARAM(0), LPARAM(0));
+        }
+    }
+}
+
+unsafe fn create(devices: &str) -> Result<HWND, windows::core::Error> {
+    let instance = GetModuleHandleW(None)?;
+    let class = WNDCLASSW {
+        lpfnWndProc: Some(procedure),
+        hInstance: instance.into(),
+        lpszClassName: w!("m2k"),
+        ..Default::default()
+    };
+    RegisterClassW(&class);
+
+    // Never shown, it only receives the messages of the icon
+    let window = CreateWindowExW(
+        WINDOW_EX_STYLE(0),
+        w!("m2k"),
+        w!("m2k"),
+        WINDOW_STYLE(0),
+        0,
+        0,
+        0,
+        0,
+        None,
+        None,
+        instance,
+        None,
+    );
+    if window.0 == 0 {
+        return Err(windows::core::Error::from_win32());
+    }
+
+    let mut icon = icon(window);
+    icon.uFlags = NIF_ICON | NIF_MESSAGE | NIF_TIP;
+    icon.uCallbackMessage = WM_TRAY;
+    icon.hIcon = LoadIconW(None, IDI_APPLICATION)?;
+    // The tooltip is cut off if it doesn't fit
+    let tip = wide(&format!("m2k - {devices}"));
+    let len = tip.len().min(icon.szTip.len()) - 1;
+    icon.szTip[..len].copy_from_slice(&tip[..len]);
+    if !Shell_NotifyIconW(NIM_ADD, &icon).as_bool() {
+        let _ = DestroyWindow(window);
+        return Err(windows::core::Error::from_win32());
+    }
+    Ok(window)
+}
+
+unsafe fn run() {
+    let mut message = MSG::default();
+    while GetMessageW(&mut message, None, 0, 0).as_bool() {
+        TranslateMessage(&message);
+        DispatchMessageW(&message);
+    }
+}
+
+fn icon(window: HWND) -> NOTIFYICONDATAW {
+    NOTIFYICONDATAW {
+        cbSize: std::mem::size_of::<NOTIFYICONDATAW>() as u32,
+        hWnd: window,
+        uID: 1,
+        ..Default::default()
+    }
+}
+
+unsafe extern "system" fn procedure(
+    window: HWND,
+    message: u32,
+    wparam: WPARAM,
+    lparam: LPARAM,
+) -> LRESULT {
+    match message {
+        WM_TRAY if matches!(lparam.0 as u32, WM_LBUTTONUP | WM_RBUTTONUP) => {
+            if let Err(error) = menu(window) {
+                report_error(error);
+            }
+            LRESULT(0)
+        }
+        WM_COMMAND if wparam.0 & 0xFFFF == QUIT => {
+            STATE.with(|state| {
+                if let Some(state) = &*state.borrow() {
+                    state.exit.request();
+                }
+            });
+            LRESULT(0)
+        }
+        WM_DESTROY => {
+            Shell_NotifyIconW(NIM_DELETE, &icon(window));
+            PostQuitMessage(0);
+            LRESULT(0)
+        }
+        _ => DefWindowProcW(window, message, wparam, lparam),
+    }
+}
+
+unsafe fn menu(window: HWND) -> Result<(), windows::core::Error> {
+    let menu = CreatePopupMenu()?;
+    STATE.with(|state| {
+        if let Some(state) = &*state.borrow() {
+            AppendMenuW(
+                menu,
+                MF_STRING | MF_GRAYED,
+                0,
+                PCWSTR(state.devices.as_ptr()),
+            )?;
+            AppendMenuW(menu, MF_SEPARATOR, 0, None)?;
+        }
+        Ok::<_, windows::core::Error>(())
+    })?;
+    AppendMenuW(menu, MF_STRING, QUIT, w!("Quit"))?;
+
+    let mut cursor = POINT::default();
+    GetCursorPos(&mut cursor)?;
+    // Without this the menu doesn't close when clicking elsewhere
+    SetForegroundWindow(window);
+    TrackPopupMenu(menu, TPM_RIGHTBUTTON, cursor.x, cursor.y, 0, window, None);
+    DestroyMenu(menu)
+}
+
+// Null terminated UTF-16
+fn wide(text: &str) -> Vec<u16> {
+    text.encode_utf16().chain([0]).collect()
+}
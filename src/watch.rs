@@ -0,0 +1,64 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use notify::{event::EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::{
+    mappings::{Mappings, SharedMappings},
+    report_error, Error,
+};
+
+// Editors often replace the file on save instead of writing to it, so the
+// parent directories are watched rather than the files themselves. A change to
+// any of the files reloads all of them since they're merged together.
+pub fn watch_mappings(
+    paths: &[PathBuf],
+    mappings: SharedMappings,
+) -> Result<RecommendedWatcher, Error> {
+    let paths = paths
+        .iter()
+        .map(|path| path.canonicalize())
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut directories: Vec<PathBuf> = paths
+        .iter()
+        .map(|path| path.parent().map(Path::to_path_buf).unwrap_or_default())
+        .collect();
+    directories.sort();
+    directories.dedup();
+
+    let mut watcher = notify::recommended_watcher({
+        let paths = paths.clone();
+        move |event: notify::Result<notify::Event>| match event {
+            Ok(event) => {
+                if let Some(changed) = paths.iter().find(|path| is_change_to(&event, path)) {
+                    reload(&paths, changed, &mappings);
+                }
+            }
+            Err(error) => report_error(error),
+        }
+    })?;
+    for directory in &directories {
+        watcher.watch(directory, RecursiveMode::NonRecursive)?;
+    }
+
+    Ok(watcher)
+}
+
+fn is_change_to(event: &notify::Event, path: &PathBuf) -> bool {
+    matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) && event.paths.contains(path)
+}
+
+fn reload(paths: &[PathBuf], changed: &Path, mappings: &SharedMappings) {
+    // Keep the current mappings around if the new ones are invalid
+    match Mappings::from_files(paths) {
+        Ok(reloaded) => {
+            let mut mappings = mappings.write().unwrap();
+            reloaded.keep_layer(&mappings);
+            *mappings = Arc::new(reloaded);
+            status!("Reloaded {}", changed.display());
+        }
+        Err(error) => report_error(error),
+    }
+}